@@ -0,0 +1,52 @@
+use ic_kit::{ic, Principal};
+
+/// Class byte of reserved principals, they can't sign messages
+const RESERVED: u8 = 0x7f;
+
+/// A principal holding balance the canister manages, derived from the canister id
+/// so nobody holds its key and only the canister can move its EMP
+fn derived(tag: u8) -> Principal {
+    let mut bytes = ic::id().as_slice().to_vec();
+    bytes.push(tag);
+    bytes.push(RESERVED);
+    Principal::from_slice(&bytes)
+}
+
+/// Whether a principal is one of the canister's reserved accounts
+pub fn is_reserved(principal: &Principal) -> bool {
+    let id = ic::id();
+    let bytes = principal.as_slice();
+    bytes.len() == id.as_slice().len() + 2
+        && bytes.starts_with(id.as_slice())
+        && bytes.last() == Some(&RESERVED)
+}
+
+/// Bankroll that game payouts are paid from
+pub fn house() -> Principal {
+    derived(1)
+}
+
+/// Pot of the running lottery round, and what rolls over from past rounds
+pub fn lottery_pot() -> Principal {
+    derived(2)
+}
+
+/// Principal locked in staking positions
+pub fn staking_pool() -> Principal {
+    derived(3)
+}
+
+/// Budget staking yield is paid from, funded by custodians and early exit penalties
+pub fn staking_rewards() -> Principal {
+    derived(4)
+}
+
+/// Treasury collecting fees, game losses and shop revenue, spent by custodians
+pub fn treasury() -> Principal {
+    derived(5)
+}
+
+/// EMP held while a marketplace sale or auction settles
+pub fn escrow() -> Principal {
+    derived(6)
+}
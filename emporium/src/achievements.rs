@@ -0,0 +1,390 @@
+use crate::audit;
+use crate::dip20;
+use crate::emission;
+use crate::ledger::{self, _is_auth};
+use crate::lock;
+use crate::token_proxy::{_DIP721v2Proxy, GenericValue};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Max badges minted per heartbeat
+const BADGES_PER_TICK: usize = 4;
+/// Badges are dropped after this many failed mints
+const MAX_BADGE_ATTEMPTS: u32 = 10;
+
+/// What a user has to reach to unlock an achievement
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum Criterion {
+    /// daily streak in any guild
+    DailyStreak(u64),
+    /// work streak in any guild
+    WorkStreak(u64),
+    /// EMP earned across all guilds
+    TotalRewards(u64),
+    /// items bought in the shop
+    Purchases(u64),
+}
+
+impl Criterion {
+    fn goal(&self) -> u64 {
+        match self {
+            Criterion::DailyStreak(goal)
+            | Criterion::WorkStreak(goal)
+            | Criterion::TotalRewards(goal)
+            | Criterion::Purchases(goal) => *goal,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Achievement {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub criterion: Criterion,
+    /// EMP awarded on unlock
+    pub bonus: u64,
+    /// mint a badge NFT on unlock
+    pub badge: bool,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Unlock {
+    pub achievement_id: String,
+    pub unlocked_at: u64,
+    /// badge token id, once minted
+    pub badge: Option<Nat>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct PendingBadge {
+    pub discord_id: String,
+    pub achievement_id: String,
+    /// taken from the definition when queued, so it can be removed meanwhile
+    pub name: String,
+    pub description: String,
+    pub to: Principal,
+    pub attempts: u32,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Achievements {
+    pub definitions: BTreeMap<String, Achievement>,
+    pub unlocked: HashMap<String, Vec<Unlock>>,
+    pub purchases: HashMap<String, u64>,
+    pub pending_badges: VecDeque<PendingBadge>,
+}
+
+impl Default for Achievements {
+    fn default() -> Self {
+        let achievement = |id: &str, name: &str, description: &str, criterion, bonus| {
+            (
+                id.to_string(),
+                Achievement {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                    description: description.to_string(),
+                    criterion,
+                    bonus,
+                    badge: true,
+                },
+            )
+        };
+        Self {
+            definitions: BTreeMap::from([
+                achievement(
+                    "daily_streak_30",
+                    "Dedicated",
+                    "Claim daily rewards 30 days in a row",
+                    Criterion::DailyStreak(30),
+                    500,
+                ),
+                achievement(
+                    "rewards_1000",
+                    "Grinder",
+                    "Earn 1000 EMP",
+                    Criterion::TotalRewards(1000),
+                    100,
+                ),
+                achievement(
+                    "first_purchase",
+                    "Customer",
+                    "Buy your first item in the shop",
+                    Criterion::Purchases(1),
+                    0,
+                ),
+            ]),
+            unlocked: HashMap::new(),
+            purchases: HashMap::new(),
+            pending_badges: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct AchievementProgress {
+    pub achievement: Achievement,
+    pub progress: u64,
+    pub unlocked_at: Option<u64>,
+    pub badge: Option<Nat>,
+}
+
+thread_local! {
+  static ACHIEVEMENTS: RefCell<Achievements> = RefCell::new(Achievements::default());
+  static MINTING: RefCell<Option<u64>> = RefCell::new(None);
+}
+
+pub fn with<T, F: FnOnce(&Achievements) -> T>(f: F) -> T {
+    ACHIEVEMENTS.with(|achievements| f(&achievements.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Achievements) -> T>(f: F) -> T {
+    ACHIEVEMENTS.with(|achievements| f(&mut achievements.borrow_mut()))
+}
+
+/// Current value of a criterion for a user
+fn progress(criterion: &Criterion, discord_id: &str) -> u64 {
+    ledger::with(|ledger| {
+        let user = match ledger.users.get(discord_id) {
+            Some(user) => user,
+            None => return 0,
+        };
+        let best_streak = |streak: fn(&ledger::GuildStats) -> u64| {
+            user.guilds.values().map(streak).max().unwrap_or(0)
+        };
+        match criterion {
+            Criterion::DailyStreak(_) => best_streak(|stats| stats.daily.streak),
+            Criterion::WorkStreak(_) => best_streak(|stats| stats.work.streak),
+            Criterion::TotalRewards(_) => user.total_rewards,
+            Criterion::Purchases(_) => with(|a| a.purchases.get(discord_id).copied().unwrap_or(0)),
+        }
+    })
+}
+
+/// Unlock any achievements the user now qualifies for, paying their bonus and
+/// queueing their badge. Called after each reward and purchase.
+/// Returns the newly unlocked achievements.
+pub fn check(discord_id: &str) -> Vec<Achievement> {
+    let principal = match ledger::with(|ledger| ledger.users.get(discord_id).map(|u| u.principal)) {
+        Some(principal) => principal,
+        None => return vec![],
+    };
+    let has_nft_canister = ledger::with(|ledger| ledger.nft_canister.is_some());
+    let now = ic::time();
+
+    let candidates: Vec<Achievement> = with(|a| {
+        let unlocked = a.unlocked.get(discord_id);
+        a.definitions
+            .values()
+            .filter(|def| unlocked.map_or(true, |u| !u.iter().any(|u| u.achievement_id == def.id)))
+            .cloned()
+            .collect()
+    });
+    let mut unlocked: Vec<Achievement> = candidates
+        .into_iter()
+        .filter(|def| progress(&def.criterion, discord_id) >= def.criterion.goal())
+        .collect();
+
+    // pay bonuses before recording the unlocks, achievements whose bonus can't be
+    // paid now stay locked and unlock on a later check
+    let bonus: u64 = unlocked.iter().map(|def| def.bonus).sum();
+    if bonus > 0 {
+        match emission::spend(bonus).and_then(|_| dip20::_mint_reward(principal, Nat::from(bonus)))
+        {
+            Ok(()) => ledger::with_mut(|ledger| {
+                if let Some(user) = ledger.users.get_mut(discord_id) {
+                    user.total_rewards += bonus;
+                }
+                ledger.log_reward(discord_id, bonus, "achievement", None);
+            }),
+            Err(e) => {
+                ic::print(format!("achievement bonus not paid: {}", e));
+                unlocked.retain(|def| def.bonus == 0);
+            }
+        }
+    }
+
+    with_mut(|a| {
+        for def in unlocked.iter() {
+            a.unlocked
+                .entry(discord_id.to_string())
+                .or_default()
+                .push(Unlock {
+                    achievement_id: def.id.clone(),
+                    unlocked_at: now,
+                    badge: None,
+                });
+            if def.badge && has_nft_canister {
+                a.pending_badges.push_back(PendingBadge {
+                    discord_id: discord_id.to_string(),
+                    achievement_id: def.id.clone(),
+                    name: def.name.clone(),
+                    description: def.description.clone(),
+                    to: principal,
+                    attempts: 0,
+                });
+            }
+        }
+    });
+
+    unlocked
+}
+
+/// Count a shop purchase towards achievements
+pub fn record_purchase(discord_id: &str) -> Vec<Achievement> {
+    with_mut(|a| *a.purchases.entry(discord_id.to_string()).or_default() += 1);
+    check(discord_id)
+}
+
+/// Format unlocked achievements for a bot response
+pub fn announce(unlocked: &[Achievement]) -> String {
+    unlocked
+        .iter()
+        .map(|def| {
+            let bonus = if def.bonus > 0 {
+                format!(" `+{} EMP`", def.bonus)
+            } else {
+                String::new()
+            };
+            format!("\n:trophy: Achievement unlocked: **{}**{}", def.name, bonus)
+        })
+        .collect()
+}
+
+async fn mint_badge(contract: &Principal, badge: &PendingBadge) -> Result<Nat, String> {
+    let token_id = ledger::next_token_id(contract).await?;
+    let properties = vec![
+        (
+            "type".to_string(),
+            GenericValue::TextContent("badge".into()),
+        ),
+        (
+            "achievement".to_string(),
+            GenericValue::TextContent(badge.achievement_id.clone()),
+        ),
+        (
+            "name".to_string(),
+            GenericValue::TextContent(badge.name.clone()),
+        ),
+        (
+            "description".to_string(),
+            GenericValue::TextContent(badge.description.clone()),
+        ),
+    ];
+    // mint replies with a transaction id, not the token id
+    _DIP721v2Proxy::_mint(contract, &badge.to, &token_id, properties).await?;
+    Ok(token_id)
+}
+
+/// Mint queued badges through the DIP721 canister, one at a time so token ids
+/// don't collide. Called from the canister heartbeat.
+pub async fn mint_badges() {
+    let contract = match ledger::with(|ledger| ledger.nft_canister) {
+        Some(contract) => contract,
+        None => return,
+    };
+    if !lock::acquire(&MINTING) {
+        return;
+    }
+
+    for _ in 0..BADGES_PER_TICK {
+        let badge = match with(|a| a.pending_badges.front().cloned()) {
+            Some(badge) => badge,
+            None => break,
+        };
+        match mint_badge(&contract, &badge).await {
+            Ok(token_id) => with_mut(|a| {
+                a.pending_badges.pop_front();
+                if let Some(unlock) = a.unlocked.get_mut(&badge.discord_id).and_then(|u| {
+                    u.iter_mut()
+                        .find(|u| u.achievement_id == badge.achievement_id)
+                }) {
+                    unlock.badge = Some(token_id);
+                }
+            }),
+            // retried from the back of the queue, so a failing badge doesn't hold up the rest
+            Err(e) => {
+                ic::print(format!("badge mint failed: {}", e));
+                with_mut(|a| {
+                    if let Some(mut badge) = a.pending_badges.pop_front() {
+                        badge.attempts += 1;
+                        if badge.attempts < MAX_BADGE_ATTEMPTS {
+                            a.pending_badges.push_back(badge);
+                        } else {
+                            ic::print(format!(
+                                "dropping {} badge of {} after {} attempts",
+                                badge.achievement_id, badge.discord_id, badge.attempts
+                            ));
+                        }
+                    }
+                });
+                break;
+            }
+        }
+    }
+
+    lock::release(&MINTING);
+}
+
+/// Get every achievement with the user's progress towards it
+#[query]
+#[candid_method(query)]
+fn user_achievements(discord_id: String) -> Vec<AchievementProgress> {
+    let definitions: Vec<Achievement> = with(|a| a.definitions.values().cloned().collect());
+    let unlocked = with(|a| a.unlocked.get(&discord_id).cloned().unwrap_or_default());
+
+    definitions
+        .into_iter()
+        .map(|achievement| {
+            let unlock = unlocked.iter().find(|u| u.achievement_id == achievement.id);
+            AchievementProgress {
+                progress: progress(&achievement.criterion, &discord_id)
+                    .min(achievement.criterion.goal()),
+                unlocked_at: unlock.map(|u| u.unlocked_at),
+                badge: unlock.and_then(|u| u.badge.clone()),
+                achievement,
+            }
+        })
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_achievements() -> Vec<Achievement> {
+    with(|a| a.definitions.values().cloned().collect())
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Add or replace an achievement definition
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_achievement(achievement: Achievement) {
+    audit::record_ok("set_achievement", format!("{:?}", achievement));
+    with_mut(|a| a.definitions.insert(achievement.id.clone(), achievement));
+}
+
+/// Remove an achievement definition, users keep their unlocks and badges
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn remove_achievement(id: String) -> Result<(), String> {
+    let res = match with_mut(|a| a.definitions.remove(&id)) {
+        Some(_) => Ok(()),
+        None => Err("Achievement not found".to_string()),
+    };
+    audit::record(
+        ic::caller(),
+        "remove_achievement",
+        format!("id: {}", id),
+        &res,
+    );
+    res
+}
+
+// END CUSTODIAN METHODS //
@@ -0,0 +1,563 @@
+use crate::accounts;
+use crate::audit;
+use crate::dip20::{_history_inc, _spend_allowance, _transfer, queue_cap_record, transfer_event};
+use crate::ledger::{self, _is_auth};
+use crate::lock;
+use crate::maintenance::{self, Subsystem};
+use crate::metrics;
+use crate::moderation;
+use crate::rate_limit;
+use crate::tip::ensure_spendable;
+use crate::token_proxy::{_DIP721v2Proxy, GenericValue};
+use crate::treasury::{self, Category};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const ONE_MINUTE: u64 = 60_000_000_000;
+const ONE_DAY: u64 = 86_400_000_000_000;
+const MAX_NAME_SIZE: usize = 100;
+/// Ended auctions settled per heartbeat
+const AUCTIONS_PER_TICK: usize = 3;
+/// Deliveries tried before an auction is marked failed
+const MAX_SETTLE_ATTEMPTS: u32 = 10;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AuctionConfig {
+    /// bids this close to the end push it back to this long after the bid, in nanoseconds
+    pub extension: u64,
+    pub max_duration: u64,
+}
+
+impl Default for AuctionConfig {
+    fn default() -> Self {
+        Self {
+            extension: 5 * ONE_MINUTE,
+            max_duration: 30 * ONE_DAY,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum AuctionItem {
+    /// minted to the winner with these properties
+    Mint(Vec<(String, GenericValue)>),
+    /// token held by the canister, transferred to the winner
+    Token(Nat),
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Bid {
+    pub discord_id: String,
+    pub principal: Principal,
+    pub amount: Nat,
+    pub placed_at: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq)]
+pub enum AuctionState {
+    Open,
+    /// ended, the item is being delivered to the winner
+    Settling,
+    Settled {
+        token_id: Nat,
+    },
+    /// ended without bids
+    Unsold,
+    Cancelled,
+    /// the item couldn't be delivered, the winning bid stays escrowed until cancelled
+    Failed(String),
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Auction {
+    pub id: u64,
+    pub name: String,
+    pub item: AuctionItem,
+    pub reserve_price: Nat,
+    pub min_increment: Nat,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    /// escrowed until the auction settles, refunded when outbid
+    pub highest_bid: Option<Bid>,
+    pub bids: u64,
+    pub state: AuctionState,
+    /// failed deliveries so far
+    pub attempts: u32,
+    /// token id taken for a `Mint` item before minting it
+    pub token_id: Option<Nat>,
+}
+
+impl Auction {
+    fn min_bid(&self) -> Nat {
+        match &self.highest_bid {
+            Some(bid) => bid.amount.clone() + self.min_increment.clone(),
+            None => self.reserve_price.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Auctions {
+    pub config: AuctionConfig,
+    pub next_id: u64,
+    pub auctions: BTreeMap<u64, Auction>,
+}
+
+impl Auctions {
+    /// Close the auctions that ended, returning up to `limit` to settle. Auctions
+    /// whose delivery failed before go after the others.
+    fn ended(&mut self, now: u64, limit: usize) -> Vec<Auction> {
+        for auction in self.auctions.values_mut() {
+            if auction.state == AuctionState::Open && auction.ends_at <= now {
+                auction.state = match auction.highest_bid {
+                    Some(_) => AuctionState::Settling,
+                    None => AuctionState::Unsold,
+                };
+            }
+        }
+        let mut settling: Vec<&Auction> = self
+            .auctions
+            .values()
+            .filter(|auction| auction.state == AuctionState::Settling)
+            .collect();
+        settling.sort_by_key(|auction| auction.attempts);
+        settling.into_iter().take(limit).cloned().collect()
+    }
+}
+
+thread_local! {
+  static AUCTIONS: RefCell<Auctions> = RefCell::new(Auctions::default());
+  static SETTLING: RefCell<Option<u64>> = RefCell::new(None);
+}
+
+pub fn with<T, F: FnOnce(&Auctions) -> T>(f: F) -> T {
+    AUCTIONS.with(|auctions| f(&auctions.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Auctions) -> T>(f: F) -> T {
+    AUCTIONS.with(|auctions| f(&mut auctions.borrow_mut()))
+}
+
+/// Pay an escrowed bid back to its bidder
+fn refund(caller: Principal, bid: &Bid, now: u64) {
+    let escrow = accounts::escrow();
+    _transfer(escrow, bid.principal, bid.amount.clone());
+    queue_cap_record(transfer_event(
+        caller,
+        "auction_refund",
+        escrow,
+        bid.principal,
+        bid.amount.clone(),
+        Nat::from(0),
+        now,
+    ));
+}
+
+/// Bid on an open auction, escrowing the bid and refunding the bidder it outbids.
+/// Raising your own bid only escrows the difference. Like a tip, the bot needs an
+/// allowance from the bidder covering the bid.
+#[update]
+#[candid_method]
+fn bid(discord_id: String, auction_id: u64, amount: Nat) -> Result<String, String> {
+    let res = _bid(discord_id, auction_id, amount);
+    metrics::observe("bid", &res);
+    res
+}
+
+fn _bid(discord_id: String, auction_id: u64, amount: Nat) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Shop)?;
+    let caller = ic::caller();
+    rate_limit::check("bid", caller, Some(&discord_id))?;
+    moderation::ensure_allowed(&discord_id)?;
+
+    let now = ic::time();
+    let auction = with(|a| a.auctions.get(&auction_id).cloned()).ok_or("Auction not found")?;
+    if auction.state != AuctionState::Open || now >= auction.ends_at {
+        return Err(format!("Auction #{} has ended", auction_id));
+    }
+    let min_bid = auction.min_bid();
+    if amount < min_bid {
+        return Err(format!(
+            "<@{}>, the minimum bid is `{} EMP`",
+            discord_id, min_bid
+        ));
+    }
+    let player = ledger::with(|ledger| ledger.users.get(&discord_id).map(|u| u.principal))
+        .ok_or_else(|| format!("<@{}>, you are not registered", discord_id))?;
+
+    let outbid = auction.highest_bid.clone();
+    let raise = matches!(&outbid, Some(bid) if bid.principal == player);
+    let owed = match &outbid {
+        Some(bid) if raise => amount.clone() - bid.amount.clone(),
+        _ => amount.clone(),
+    };
+    let delegated = ensure_spendable(caller, &discord_id, player, &owed)?;
+
+    let escrow = accounts::escrow();
+    _transfer(player, escrow, owed.clone());
+    if delegated {
+        _spend_allowance(player, caller, owed.clone());
+    }
+    queue_cap_record(transfer_event(
+        caller,
+        "auction_bid",
+        player,
+        escrow,
+        owed,
+        Nat::from(0),
+        now,
+    ));
+    if let Some(bid) = outbid.filter(|_| !raise) {
+        refund(caller, &bid, now);
+    }
+    _history_inc();
+
+    let ends_at = with_mut(|a| {
+        let extension = a.config.extension;
+        let auction = a.auctions.get_mut(&auction_id).unwrap();
+        auction.highest_bid = Some(Bid {
+            discord_id: discord_id.clone(),
+            principal: player,
+            amount: amount.clone(),
+            placed_at: now,
+        });
+        auction.bids += 1;
+        // anti-sniping, a late bid gives everyone else time to answer
+        auction.ends_at = auction.ends_at.max(now + extension);
+        auction.ends_at
+    });
+
+    Ok(format!(
+        "<@{}> is the highest bidder on **{}** with `{} EMP`, ending <t:{}:R>",
+        discord_id,
+        auction.name,
+        amount,
+        ends_at / 1_000_000_000
+    ))
+}
+
+/// Take a token id for an auction's `Mint` item, kept on the auction
+async fn take_token_id(contract: &Principal, auction_id: u64) -> Result<Nat, String> {
+    let token_id = ledger::next_token_id(contract).await?;
+    with_mut(|a| {
+        if let Some(auction) = a.auctions.get_mut(&auction_id) {
+            auction.token_id = Some(token_id.clone());
+        }
+    });
+    Ok(token_id)
+}
+
+/// Deliver the item of an auction to its winner, returning the token id. A `Mint`
+/// item's token id is kept before minting, so a retry after a lost reply finds the
+/// token delivered instead of minting it again.
+async fn deliver(contract: &Principal, auction: &Auction, to: &Principal) -> Result<Nat, String> {
+    let token_id = match (&auction.item, &auction.token_id) {
+        (AuctionItem::Token(token_id), _) | (AuctionItem::Mint(_), Some(token_id)) => {
+            token_id.clone()
+        }
+        (AuctionItem::Mint(_), None) => take_token_id(contract, auction.id).await?,
+    };
+
+    let token_id = match _DIP721v2Proxy::_owner_of(contract, &token_id).await {
+        Ok(Some(owner)) if owner == *to => return Ok(token_id),
+        // another mint took the id, take a new one
+        Ok(Some(_)) if matches!(auction.item, AuctionItem::Mint(_)) => {
+            take_token_id(contract, auction.id).await?
+        }
+        Ok(_) => token_id,
+        Err(e) if e == "TokenNotFound" => token_id,
+        Err(e) => return Err(e),
+    };
+    match &auction.item {
+        AuctionItem::Mint(properties) => {
+            _DIP721v2Proxy::_mint(contract, to, &token_id, properties.clone()).await?;
+        }
+        AuctionItem::Token(_) => {
+            _DIP721v2Proxy::_transfer(contract, to, &token_id).await?;
+        }
+    }
+    Ok(token_id)
+}
+
+/// Settle ended auctions: the winner gets the item and the winning bid goes to the
+/// treasury. Failed deliveries are retried after the other ended auctions, and the
+/// auction is marked failed after `MAX_SETTLE_ATTEMPTS`. Called from the canister
+/// heartbeat.
+pub async fn settle() {
+    let contract = match ledger::with(|ledger| ledger.nft_canister) {
+        Some(contract) => contract,
+        None => return,
+    };
+    if !lock::acquire(&SETTLING) {
+        return;
+    }
+
+    let now = ic::time();
+    let batch = with_mut(|a| a.ended(now, AUCTIONS_PER_TICK));
+
+    for auction in batch {
+        let bid = match &auction.highest_bid {
+            Some(bid) => bid.clone(),
+            None => continue,
+        };
+        match deliver(&contract, &auction, &bid.principal).await {
+            Ok(token_id) => {
+                let now = ic::time();
+                treasury::deposit(accounts::escrow(), bid.amount.clone(), Category::Shop);
+                metrics::shop_sale(&auction.name, bid.amount.clone());
+                _history_inc();
+                with_mut(|a| {
+                    if let Some(auction) = a.auctions.get_mut(&auction.id) {
+                        auction.state = AuctionState::Settled {
+                            token_id: token_id.clone(),
+                        };
+                    }
+                });
+                queue_cap_record(IndefiniteEvent {
+                    caller: ic::id(),
+                    operation: "auction_settle".to_string(),
+                    details: vec![
+                        ("auction".to_string(), DetailValue::U64(auction.id)),
+                        ("token_id".to_string(), DetailValue::from(token_id)),
+                        ("from".to_string(), DetailValue::from(accounts::escrow())),
+                        ("to".to_string(), DetailValue::from(accounts::treasury())),
+                        ("winner".to_string(), DetailValue::from(bid.principal)),
+                        ("amount".to_string(), DetailValue::from(bid.amount)),
+                        ("timestamp".to_string(), DetailValue::U64(now)),
+                    ],
+                });
+            }
+            Err(e) => {
+                ic::print(format!("auction #{} settlement failed: {}", auction.id, e));
+                with_mut(|a| {
+                    if let Some(auction) = a.auctions.get_mut(&auction.id) {
+                        auction.attempts += 1;
+                        if auction.attempts >= MAX_SETTLE_ATTEMPTS {
+                            auction.state = AuctionState::Failed(e);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    lock::release(&SETTLING);
+}
+
+/// Get auctions, newest first. Closed ones are only included if asked for.
+#[query]
+#[candid_method(query)]
+fn get_auctions(include_closed: bool, limit: u64) -> Vec<Auction> {
+    with(|a| {
+        a.auctions
+            .values()
+            .rev()
+            .filter(|auction| include_closed || auction.state == AuctionState::Open)
+            .take((limit as usize).min(MAX_PAGE_SIZE))
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_auction(auction_id: u64) -> Option<Auction> {
+    with(|a| a.auctions.get(&auction_id).cloned())
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Auction an item for `duration` nanoseconds. Bids start at the reserve price and
+/// must beat the highest bid by the minimum increment. A `Token` item must be held
+/// by the canister.
+#[update(guard = "_is_auth")]
+#[candid_method]
+async fn create_auction(
+    name: String,
+    item: AuctionItem,
+    reserve_price: Nat,
+    min_increment: Nat,
+    duration: u64,
+) -> Result<u64, String> {
+    let args = format!(
+        "name: {}, item: {:?}, reserve_price: {}, min_increment: {}, duration: {}",
+        name, item, reserve_price, min_increment, duration
+    );
+    let res = _create_auction(name, item, reserve_price, min_increment, duration).await;
+    audit::record(ic::caller(), "create_auction", args, &res);
+    res
+}
+
+async fn _create_auction(
+    name: String,
+    item: AuctionItem,
+    reserve_price: Nat,
+    min_increment: Nat,
+    duration: u64,
+) -> Result<u64, String> {
+    if name.is_empty() || name.len() > MAX_NAME_SIZE {
+        return Err(format!("Name must be 1 to {} bytes", MAX_NAME_SIZE));
+    }
+    if reserve_price == 0 || min_increment == 0 {
+        return Err("Reserve price and minimum increment must be more than 0".to_string());
+    }
+    let max_duration = with(|a| a.config.max_duration);
+    if duration == 0 || duration > max_duration {
+        return Err(format!(
+            "Duration must be more than 0 and at most {} days",
+            max_duration / ONE_DAY
+        ));
+    }
+    let contract = ledger::with(|ledger| ledger.nft_canister).ok_or("No NFT canister set")?;
+    if let AuctionItem::Token(token_id) = &item {
+        if _DIP721v2Proxy::_owner_of(&contract, token_id).await? != Some(ic::id()) {
+            return Err("The canister doesn't own this token".to_string());
+        }
+    }
+
+    let now = ic::time();
+    Ok(with_mut(|a| {
+        let id = a.next_id;
+        a.next_id += 1;
+        a.auctions.insert(
+            id,
+            Auction {
+                id,
+                name,
+                item,
+                reserve_price,
+                min_increment,
+                starts_at: now,
+                ends_at: now + duration,
+                highest_bid: None,
+                bids: 0,
+                state: AuctionState::Open,
+                attempts: 0,
+                token_id: None,
+            },
+        );
+        id
+    }))
+}
+
+/// Cancel an open auction, or one whose delivery failed, refunding the highest bid
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn cancel_auction(auction_id: u64) -> Result<(), String> {
+    let caller = ic::caller();
+    let res = with_mut(|a| {
+        let auction = a.auctions.get_mut(&auction_id).ok_or("Auction not found")?;
+        if !matches!(auction.state, AuctionState::Open | AuctionState::Failed(_)) {
+            return Err("Only open or failed auctions can be cancelled".to_string());
+        }
+        auction.state = AuctionState::Cancelled;
+        Ok(auction.highest_bid.clone())
+    })
+    .map(|bid| {
+        if let Some(bid) = bid {
+            refund(caller, &bid, ic::time());
+            _history_inc();
+        }
+    });
+    audit::record(caller, "cancel_auction", auction_id.to_string(), &res);
+    res
+}
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_auction_config(config: AuctionConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res = if config.max_duration == 0 {
+        Err("Max duration must be more than 0".to_string())
+    } else {
+        with_mut(|a| a.config = config);
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_auction_config", args, &res);
+    res
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_auction_config() -> AuctionConfig {
+    with(|a| a.config.clone())
+}
+
+// END CUSTODIAN METHODS //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auction(id: u64, ends_at: u64, bid: Option<u64>) -> Auction {
+        Auction {
+            id,
+            name: format!("item {}", id),
+            item: AuctionItem::Token(Nat::from(id)),
+            reserve_price: Nat::from(100),
+            min_increment: Nat::from(10),
+            starts_at: 0,
+            ends_at,
+            highest_bid: bid.map(|amount| Bid {
+                discord_id: "000000000000000000".to_string(),
+                principal: Principal::anonymous(),
+                amount: Nat::from(amount),
+                placed_at: 0,
+            }),
+            bids: bid.map_or(0, |_| 1),
+            state: AuctionState::Open,
+            attempts: 0,
+            token_id: None,
+        }
+    }
+
+    fn auctions(list: Vec<Auction>) -> Auctions {
+        Auctions {
+            auctions: list.into_iter().map(|a| (a.id, a)).collect(),
+            ..Auctions::default()
+        }
+    }
+
+    #[test]
+    fn min_bid_starts_at_reserve_then_adds_increment() {
+        assert_eq!(auction(0, 10, None).min_bid(), Nat::from(100));
+        assert_eq!(auction(0, 10, Some(150)).min_bid(), Nat::from(160));
+    }
+
+    #[test]
+    fn ended_closes_auctions_by_bids() {
+        let mut a = auctions(vec![
+            auction(0, 10, Some(100)),
+            auction(1, 10, None),
+            auction(2, 20, Some(100)),
+        ]);
+        let ended = a.ended(10, 10);
+        assert_eq!(ended.iter().map(|a| a.id).collect::<Vec<_>>(), vec![0]);
+        assert!(a.auctions[&0].state == AuctionState::Settling);
+        assert!(a.auctions[&1].state == AuctionState::Unsold);
+        assert!(a.auctions[&2].state == AuctionState::Open);
+    }
+
+    #[test]
+    fn ended_retries_failed_deliveries_last() {
+        let mut a = auctions(vec![
+            auction(0, 10, Some(100)),
+            auction(1, 10, Some(100)),
+            auction(2, 10, Some(100)),
+        ]);
+        a.ended(10, 0);
+        a.auctions.get_mut(&0).unwrap().attempts = 2;
+        a.auctions.get_mut(&1).unwrap().attempts = 1;
+        a.auctions.get_mut(&2).unwrap().state = AuctionState::Failed("gone".to_string());
+        let ended = a.ended(10, 2);
+        assert_eq!(ended.iter().map(|a| a.id).collect::<Vec<_>>(), vec![1, 0]);
+    }
+}
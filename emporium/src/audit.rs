@@ -0,0 +1,157 @@
+use crate::dip20::insert_into_cap;
+use crate::ledger::_is_auth;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+/// Entries kept in the canister, older entries are archived to cap
+const AUDIT_LOG_SIZE: usize = 1000;
+/// Max entries archived per heartbeat
+const ARCHIVE_PER_TICK: usize = 10;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq)]
+pub enum AuditOutcome {
+    Ok,
+    Err(String),
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub caller: Principal,
+    pub method: String,
+    pub args: String,
+    pub timestamp: u64,
+    pub outcome: AuditOutcome,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct AuditLog {
+    pub next_id: u64,
+    pub entries: VecDeque<AuditEntry>,
+    /// entries evicted from the log, waiting to be written to cap
+    pub pending_archive: VecDeque<AuditEntry>,
+    pub archived: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct AuditFilter {
+    pub caller: Option<Principal>,
+    pub method: Option<String>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    /// entries matching the filter in the canister
+    pub total: u64,
+    /// entries moved to cap, query these with the cap `audit` operation
+    pub archived: u64,
+}
+
+thread_local! {
+  static AUDIT: RefCell<AuditLog> = RefCell::new(AuditLog::default());
+}
+
+pub fn with<T, F: FnOnce(&AuditLog) -> T>(f: F) -> T {
+    AUDIT.with(|audit| f(&audit.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut AuditLog) -> T>(f: F) -> T {
+    AUDIT.with(|audit| f(&mut audit.borrow_mut()))
+}
+
+/// Append a privileged call and its outcome to the audit log.
+/// `caller` is passed in, as it is not available after an await.
+pub fn record<T, E: Debug>(caller: Principal, method: &str, args: String, res: &Result<T, E>) {
+    let outcome = match res {
+        Ok(_) => AuditOutcome::Ok,
+        Err(e) => AuditOutcome::Err(format!("{:?}", e)),
+    };
+
+    with_mut(|audit| {
+        let id = audit.next_id;
+        audit.next_id += 1;
+        audit.entries.push_back(AuditEntry {
+            id,
+            caller,
+            method: method.to_string(),
+            args,
+            timestamp: ic::time(),
+            outcome,
+        });
+
+        while audit.entries.len() > AUDIT_LOG_SIZE {
+            let evicted = audit.entries.pop_front().unwrap();
+            audit.pending_archive.push_back(evicted);
+        }
+    })
+}
+
+/// Record a privileged call that can't fail
+pub fn record_ok(method: &str, args: String) {
+    record::<(), ()>(ic::caller(), method, args, &Ok(()))
+}
+
+/// Write evicted entries to cap. Called from the canister heartbeat.
+pub async fn archive() {
+    let batch: Vec<AuditEntry> = with_mut(|audit| {
+        let n = audit.pending_archive.len().min(ARCHIVE_PER_TICK);
+        audit.archived += n as u64;
+        audit.pending_archive.drain(..n).collect()
+    });
+
+    for entry in batch {
+        let outcome = match entry.outcome {
+            AuditOutcome::Ok => "ok".to_string(),
+            AuditOutcome::Err(e) => e,
+        };
+        // failed inserts are queued in the tx log and retried
+        let _ = insert_into_cap(IndefiniteEvent {
+            caller: entry.caller,
+            operation: "audit".to_string(),
+            details: vec![
+                ("id".to_string(), DetailValue::U64(entry.id)),
+                ("method".to_string(), DetailValue::Text(entry.method)),
+                ("args".to_string(), DetailValue::Text(entry.args)),
+                ("outcome".to_string(), DetailValue::Text(outcome)),
+                ("timestamp".to_string(), DetailValue::U64(entry.timestamp)),
+            ],
+        })
+        .await;
+    }
+}
+
+/// Get audit entries, newest first, optionally filtered by caller and method
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_audit_log(filter: AuditFilter, start: usize, limit: usize) -> AuditPage {
+    with(|audit| {
+        let matching: Vec<&AuditEntry> = audit
+            .entries
+            .iter()
+            .rev()
+            .filter(|e| filter.caller.map_or(true, |c| e.caller == c))
+            .filter(|e| filter.method.as_ref().map_or(true, |m| &e.method == m))
+            .collect();
+
+        AuditPage {
+            total: matching.len() as u64,
+            entries: matching
+                .into_iter()
+                .skip(start)
+                .take(limit.min(MAX_PAGE_SIZE))
+                .cloned()
+                .collect(),
+            archived: audit.archived,
+        }
+    })
+}
@@ -1,4 +1,15 @@
+use crate::accounts;
+use crate::audit;
+use crate::emission;
+use crate::events::{self, EventKind};
 use crate::ledger::*;
+use crate::lock;
+use crate::maintenance::{self, Subsystem};
+use crate::metrics;
+use crate::moderation;
+use crate::multisig::{self, Operation};
+use crate::rate_limit;
+use crate::treasury::{self, Category};
 /**
 * Module     : main.rs
 * Copyright  : 2022 Fleek
@@ -7,7 +18,7 @@ use crate::ledger::*;
 * Stability  : Experimental
 */
 use candid::{candid_method, CandidType, Deserialize, Nat};
-use cap_sdk::{insert, DetailValue, IndefiniteEvent};
+use cap_sdk::{insert, insert_many, DetailValue, IndefiniteEvent};
 use ic_cdk_macros::*;
 use ic_kit::{ic, Principal};
 use std::cell::RefCell;
@@ -87,11 +98,27 @@ pub enum TxError {
 }
 pub type TxReceipt = Result<Nat, TxError>;
 
+/// Receipt of a batch entry, the batch went through either way
+#[derive(CandidType, Debug, PartialEq)]
+pub enum BatchReceipt {
+    /// cap tx id of the entry's record
+    Recorded(Nat),
+    /// cap couldn't be reached and the record is queued for the heartbeat, with the
+    /// entry's index in the token history
+    Queued(Nat),
+}
+
+/// Max queued cap records written per heartbeat
+const CAP_RECORDS_PER_TICK: usize = 20;
+/// Max entries in a batch transfer or mint
+const MAX_BATCH_SIZE: usize = 500;
+
 thread_local! {
     pub static BALANCES: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::default());
     pub static ALLOWS: RefCell<HashMap<Principal, HashMap<Principal, Nat>>> = RefCell::new(HashMap::default());
     pub static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
     pub static TXLOG: RefCell<TxLog> = RefCell::new(TxLog::default());
+    static FLUSHING: RefCell<Option<u64>> = RefCell::new(None);
 }
 
 // #[init]
@@ -140,6 +167,15 @@ thread_local! {
 #[update]
 #[candid_method(update)]
 async fn transfer(to: Principal, value: Nat) -> TxReceipt {
+    let res = _transfer_from_caller(to, value).await;
+    metrics::observe("transfer", &res);
+    res
+}
+
+async fn _transfer_from_caller(to: Principal, value: Nat) -> TxReceipt {
+    maintenance::ensure_active(Subsystem::Transfers).map_err(TxError::Other)?;
+    rate_limit::check("transfer", ic::caller(), None).map_err(TxError::Other)?;
+    moderation::ensure_allowed_principal(ic::caller()).map_err(TxError::Other)?;
     let from = ic::caller();
     let fee = _get_fee();
     if balance_of(from) < value.clone() + fee.clone() {
@@ -148,12 +184,48 @@ async fn transfer(to: Principal, value: Nat) -> TxReceipt {
     _charge_fee(from, fee.clone());
     _transfer(from, to, value.clone());
     _history_inc();
+    _announce_transfer(from, to, &value);
     add_record(from, "transfer", from, to, value, fee, ic::time()).await
 }
 
+/// Transfer from the caller to many principals at once. The whole batch is checked
+/// up front and applied atomically, returning a receipt per entry.
+#[update]
+#[candid_method(update)]
+async fn batch_transfer(transfers: Vec<(Principal, Nat)>) -> Result<Vec<BatchReceipt>, TxError> {
+    let res = _batch_transfer_from_caller(transfers).await;
+    metrics::observe("batch_transfer", &res);
+    res
+}
+
+async fn _batch_transfer_from_caller(
+    transfers: Vec<(Principal, Nat)>,
+) -> Result<Vec<BatchReceipt>, TxError> {
+    maintenance::ensure_active(Subsystem::Transfers).map_err(TxError::Other)?;
+    rate_limit::check("batch_transfer", ic::caller(), None).map_err(TxError::Other)?;
+    moderation::ensure_allowed_principal(ic::caller()).map_err(TxError::Other)?;
+    let from = ic::caller();
+    let total = validate_batch(&transfers)?;
+    let fees = _get_fee() * Nat::from(transfers.len());
+    if balance_of(from) < total + fees {
+        return Err(TxError::InsufficientBalance);
+    }
+    let events = _batch_transfer(from, "transfer", from, &transfers);
+    Ok(insert_batch_into_cap(events).await)
+}
+
 #[update(name = "transferFrom")]
 #[candid_method(update, rename = "transferFrom")]
 async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
+    let res = _transfer_from(from, to, value).await;
+    metrics::observe("transferFrom", &res);
+    res
+}
+
+async fn _transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
+    maintenance::ensure_active(Subsystem::Transfers).map_err(TxError::Other)?;
+    rate_limit::check("transferFrom", ic::caller(), None).map_err(TxError::Other)?;
+    moderation::ensure_allowed_principal(from).map_err(TxError::Other)?;
     let owner = ic::caller();
     let from_allowance = allowance(from, owner);
     let fee = _get_fee();
@@ -166,36 +238,23 @@ async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt
     }
     _charge_fee(from, fee.clone());
     _transfer(from, to, value.clone());
-    ALLOWS.with(|a| {
-        let mut allowances = a.borrow_mut();
-        match allowances.get(&from) {
-            Some(inner) => {
-                let result = inner.get(&owner).unwrap().clone();
-                let mut temp = inner.clone();
-                if result.clone() - value.clone() - fee.clone() != 0 {
-                    temp.insert(owner, result - value.clone() - fee.clone());
-                    allowances.insert(from, temp);
-                } else {
-                    temp.remove(&owner);
-                    if temp.is_empty() {
-                        allowances.remove(&from);
-                    } else {
-                        allowances.insert(from, temp);
-                    }
-                }
-            }
-            None => {
-                unreachable!();
-            }
-        }
-    });
+    _spend_allowance(from, owner, value.clone() + fee.clone());
     _history_inc();
+    _announce_transfer(from, to, &value);
     add_record(owner, "transfer_from", from, to, value, fee, ic::time()).await
 }
 
 #[update]
 #[candid_method(update)]
 pub async fn approve(spender: Principal, value: Nat) -> TxReceipt {
+    let res = _approve(spender, value).await;
+    metrics::observe("approve", &res);
+    res
+}
+
+async fn _approve(spender: Principal, value: Nat) -> TxReceipt {
+    maintenance::ensure_active(Subsystem::Approvals).map_err(TxError::Other)?;
+    rate_limit::check("approve", ic::caller(), None).map_err(TxError::Other)?;
     let owner = ic::caller();
     let fee = _get_fee();
     if balance_of(owner) < fee.clone() {
@@ -440,7 +499,89 @@ pub fn get_user_approvals(who: Principal) -> Vec<(Principal, Nat)> {
 #[update(guard = "_is_auth")]
 #[candid_method(update, rename = "mint")]
 pub async fn mint(to: Principal, amount: Nat) -> TxReceipt {
-    // let caller = ic::caller();
+    let caller = ic::caller();
+    let args = format!("to: {}, amount: {}", to, amount);
+    let res = match multisig::ensure_direct(&Operation::Mint {
+        to,
+        amount: amount.clone(),
+    }) {
+        Ok(()) => _mint(to, amount).await,
+        Err(e) => Err(TxError::Other(e)),
+    };
+    metrics::observe("mint", &res);
+    audit::record(caller, "mint", args, &res);
+    res
+}
+
+/// Mint to many principals in a single call, returning a receipt per entry
+#[update(guard = "_is_auth")]
+#[candid_method(update)]
+pub async fn batch_mint(mints: Vec<(Principal, Nat)>) -> Result<Vec<BatchReceipt>, TxError> {
+    let caller = ic::caller();
+    let total = mints
+        .iter()
+        .fold(Nat::from(0), |total, (_, amount)| total + amount.clone());
+    let args = format!("{} mints, total: {}", mints.len(), total);
+    let res = match multisig::ensure_direct(&Operation::BatchMint(mints.clone())) {
+        Ok(()) => _batch_mint(mints).await,
+        Err(e) => Err(TxError::Other(e)),
+    };
+    metrics::observe("batch_mint", &res);
+    audit::record(caller, "batch_mint", args, &res);
+    res
+}
+
+/// Mint a reward without awaiting, so it lands in the same message as the reward's
+/// accounting. The cap record is queued for the heartbeat.
+pub fn _mint_reward(to: Principal, amount: Nat) -> Result<(), String> {
+    emission::ensure_supply(&amount)?;
+    _balance_ins(to, balance_of(to) + amount.clone());
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.total_supply += amount.clone();
+    });
+    _history_inc();
+    queue_cap_record(transfer_event(
+        Principal::anonymous(),
+        "mint",
+        ic_cdk::id(),
+        to,
+        amount,
+        Nat::from(0),
+        ic::time(),
+    ));
+    Ok(())
+}
+
+pub async fn _batch_mint(mints: Vec<(Principal, Nat)>) -> Result<Vec<BatchReceipt>, TxError> {
+    let total = validate_batch(&mints)?;
+    emission::ensure_supply(&total).map_err(TxError::Other)?;
+    let now = ic::time();
+    let events = mints
+        .into_iter()
+        .map(|(to, amount)| {
+            _balance_ins(to, balance_of(to) + amount.clone());
+            _history_inc();
+            transfer_event(
+                Principal::anonymous(),
+                "mint",
+                ic_cdk::id(),
+                to,
+                amount,
+                Nat::from(0),
+                now,
+            )
+        })
+        .collect();
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.total_supply += total;
+    });
+    Ok(insert_batch_into_cap(events).await)
+}
+
+pub async fn _mint(to: Principal, amount: Nat) -> TxReceipt {
+    emission::ensure_supply(&amount).map_err(TxError::Other)?;
     let to_balance = balance_of(to);
 
     BALANCES.with(|b| {
@@ -467,6 +608,7 @@ pub async fn mint(to: Principal, amount: Nat) -> TxReceipt {
 #[update(name = "setName", guard = "_is_auth")]
 #[candid_method(update, rename = "setName")]
 fn set_name(name: String) {
+    audit::record_ok("setName", format!("name: {}", name));
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.name = name;
@@ -476,6 +618,7 @@ fn set_name(name: String) {
 #[update(name = "setSymbol", guard = "_is_auth")]
 #[candid_method(update, rename = "setSymbol")]
 fn set_symbol(symbol: String) {
+    audit::record_ok("setSymbol", format!("symbol: {}", symbol));
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.symbol = symbol;
@@ -485,6 +628,7 @@ fn set_symbol(symbol: String) {
 #[update(name = "setLogo", guard = "_is_auth")]
 #[candid_method(update, rename = "setLogo")]
 fn set_logo(logo: String) {
+    audit::record_ok("setLogo", format!("logo: {} bytes", logo.len()));
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.logo = logo;
@@ -494,23 +638,39 @@ fn set_logo(logo: String) {
 #[update(name = "setFee", guard = "_is_auth")]
 #[candid_method(update, rename = "setFee")]
 fn set_fee(fee: Nat) {
+    if let Err(e) = multisig::ensure_direct(&Operation::SetFee(fee.clone())) {
+        ic_cdk::trap(&e);
+    }
+    audit::record_ok("setFee", format!("fee: {}", fee));
+    _set_fee(fee);
+}
+
+#[update(name = "setFeeTo", guard = "_is_auth")]
+#[candid_method(update, rename = "setFeeTo")]
+fn set_fee_to(fee_to: Principal) {
+    if let Err(e) = multisig::ensure_direct(&Operation::SetFeeTo(fee_to)) {
+        ic_cdk::trap(&e);
+    }
+    audit::record_ok("setFeeTo", format!("fee_to: {}", fee_to));
+    _set_fee_to(fee_to);
+}
+
+/* INTERNAL FNS */
+
+pub fn _set_fee(fee: Nat) {
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.fee = fee;
     });
 }
 
-#[update(name = "setFeeTo", guard = "_is_auth")]
-#[candid_method(update, rename = "setFeeTo")]
-fn set_fee_to(fee_to: Principal) {
+pub fn _set_fee_to(fee_to: Principal) {
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.fee_to = fee_to;
     });
 }
 
-/* INTERNAL FNS */
-
 pub fn _balance_ins(from: Principal, value: Nat) {
     BALANCES.with(|b| {
         let mut balances = b.borrow_mut();
@@ -542,11 +702,111 @@ pub fn _transfer(from: Principal, to: Principal, value: Nat) {
     }
 }
 
+/// Transfer from one principal to many in a single call, charging the fee per
+/// recipient. Returns the cap records for the caller to write or queue.
+/// `from` must hold the total plus fees.
+pub fn _batch_transfer(
+    caller: Principal,
+    op: &str,
+    from: Principal,
+    transfers: &[(Principal, Nat)],
+) -> Vec<IndefiniteEvent> {
+    let fee = _get_fee();
+    let now = ic::time();
+    transfers
+        .iter()
+        .map(|(to, value)| {
+            _charge_fee(from, fee.clone());
+            _transfer(from, *to, value.clone());
+            _history_inc();
+            transfer_event(caller, op, from, *to, value.clone(), fee.clone(), now)
+        })
+        .collect()
+}
+
+/// Check every entry of a batch, returning the batch total
+fn validate_batch(entries: &[(Principal, Nat)]) -> Result<Nat, TxError> {
+    if entries.is_empty() || entries.len() > MAX_BATCH_SIZE {
+        return Err(TxError::Other(format!(
+            "Batches must have between 1 and {} entries",
+            MAX_BATCH_SIZE
+        )));
+    }
+    let mut total = Nat::from(0);
+    for (i, (to, value)) in entries.iter().enumerate() {
+        if *to == Principal::anonymous() {
+            return Err(TxError::Other(format!(
+                "Entry {}: can't send to the anonymous principal",
+                i
+            )));
+        }
+        if *value == Nat::from(0) {
+            return Err(TxError::Other(format!(
+                "Entry {}: amount must be more than 0",
+                i
+            )));
+        }
+        total += value.clone();
+    }
+    Ok(total)
+}
+
+/// Deduct `amount` from the allowance `from` gave `spender`, the allowance must cover it
+pub fn _spend_allowance(from: Principal, spender: Principal, amount: Nat) {
+    ALLOWS.with(|a| {
+        let mut allowances = a.borrow_mut();
+        match allowances.get(&from) {
+            Some(inner) => {
+                let result = inner.get(&spender).unwrap().clone();
+                let mut temp = inner.clone();
+                if result.clone() - amount.clone() != 0 {
+                    temp.insert(spender, result - amount);
+                    allowances.insert(from, temp);
+                } else {
+                    temp.remove(&spender);
+                    if temp.is_empty() {
+                        allowances.remove(&from);
+                    } else {
+                        allowances.insert(from, temp);
+                    }
+                }
+            }
+            None => {
+                unreachable!();
+            }
+        }
+    });
+}
+
+/// Burn up to `amount` from `from`, returning the amount burned
+pub fn _burn(from: Principal, amount: Nat) -> Nat {
+    let from_balance = balance_of(from);
+    let burned = if from_balance < amount {
+        from_balance.clone()
+    } else {
+        amount
+    };
+
+    if from_balance.clone() - burned.clone() != 0 {
+        _balance_ins(from, from_balance - burned.clone());
+    } else {
+        _balance_rem(from);
+    }
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.total_supply -= burned.clone();
+    });
+    burned
+}
+
 pub fn _charge_fee(user: Principal, fee: Nat) {
     STATS.with(|s| {
         let stats = s.borrow();
         if stats.fee > Nat::from(0) {
-            _transfer(user, stats.fee_to, fee);
+            _transfer(user, stats.fee_to, fee.clone());
+            if stats.fee_to == accounts::treasury() {
+                treasury::record_inflow(Category::Fees, &fee);
+            }
         }
     });
 }
@@ -565,6 +825,22 @@ pub fn _get_owner() -> Principal {
     })
 }
 
+/// Announce transfers above the configured threshold, by discord id where known
+pub fn _announce_transfer(from: Principal, to: Principal, value: &Nat) {
+    if !events::is_large_transfer(value) {
+        return;
+    }
+    let display = |p: Principal| {
+        with(|ledger| ledger.principals.get(&p).cloned())
+            .map(|discord_id| format!("<@{}>", discord_id))
+            .unwrap_or_else(|| format!("`{}`", p))
+    };
+    events::publish(
+        EventKind::LargeTransfer,
+        format!("{} sent `{} EMP` to {}", display(from), value, display(to)),
+    );
+}
+
 pub fn _history_inc() {
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
@@ -572,7 +848,7 @@ pub fn _history_inc() {
     })
 }
 
-pub async fn add_record(
+pub fn transfer_event(
     caller: Principal,
     op: &str,
     from: Principal,
@@ -580,8 +856,8 @@ pub async fn add_record(
     amount: Nat,
     fee: Nat,
     timestamp: u64,
-) -> TxReceipt {
-    let ie: IndefiniteEvent = IndefiniteEvent {
+) -> IndefiniteEvent {
+    IndefiniteEvent {
         caller,
         operation: op.to_string(),
         details: Vec::from([
@@ -591,20 +867,79 @@ pub async fn add_record(
             ("fee".to_string(), DetailValue::from(fee)),
             ("timestamp".to_string(), DetailValue::from(timestamp)),
         ]),
-    };
+    }
+}
 
+pub async fn add_record(
+    caller: Principal,
+    op: &str,
+    from: Principal,
+    to: Principal,
+    amount: Nat,
+    fee: Nat,
+    timestamp: u64,
+) -> TxReceipt {
+    let ie = transfer_event(caller, op, from, to, amount, fee, timestamp);
     insert_into_cap(ie).await
 }
 
+/// Write a batch's records to cap. Balances have already moved, so if cap can't
+/// be reached the records are queued for the heartbeat and the receipts say so.
+async fn insert_batch_into_cap(events: Vec<IndefiniteEvent>) -> Vec<BatchReceipt> {
+    let count = events.len();
+    let first_index = history_size().saturating_sub(count);
+    match insert_many(events.clone().into_iter()).await {
+        Ok(first_id) => (0..count as u64)
+            .map(|i| BatchReceipt::Recorded(Nat::from(first_id + i)))
+            .collect(),
+        Err(error) => {
+            ic::print(format!("Inserting into cap failed with error: {:?}", error));
+            for ie in events {
+                queue_cap_record(ie);
+            }
+            (0..count)
+                .map(|i| BatchReceipt::Queued(Nat::from(first_index + i)))
+                .collect()
+        }
+    }
+}
+
 pub async fn insert_into_cap(ie: IndefiniteEvent) -> TxReceipt {
-    let mut tx_log = TXLOG.with(|t| t.take());
-    if let Some(failed_ie) = tx_log.ie_records.pop_front() {
-        let _ = insert_into_cap_priv(failed_ie).await;
+    let failed_ie = TXLOG.with(|t| t.borrow_mut().ie_records.pop_front());
+    if let Some(failed_ie) = failed_ie {
+        let _ = insert_into_cap_priv(failed_ie, true).await;
     }
-    insert_into_cap_priv(ie).await
+    insert_into_cap_priv(ie, false).await
 }
 
-async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
+/// Queue a record to be written to cap from the heartbeat, for batched operations
+/// that would otherwise await an insert per record
+pub fn queue_cap_record(ie: IndefiniteEvent) {
+    TXLOG.with(|t| t.borrow_mut().ie_records.push_back(ie));
+}
+
+/// Write queued and failed records to cap, oldest first. Called from the canister
+/// heartbeat.
+pub async fn flush_cap() {
+    if !lock::acquire(&FLUSHING) {
+        return;
+    }
+    for _ in 0..CAP_RECORDS_PER_TICK {
+        let ie = match TXLOG.with(|t| t.borrow_mut().ie_records.pop_front()) {
+            Some(ie) => ie,
+            None => break,
+        };
+        // failed inserts are queued again
+        if insert_into_cap_priv(ie, true).await.is_err() {
+            break;
+        }
+    }
+    lock::release(&FLUSHING);
+}
+
+/// Insert a record into cap, queueing it on failure. Retried records go back to the
+/// front of the queue, to keep the order they were made in.
+async fn insert_into_cap_priv(ie: IndefiniteEvent, retry: bool) -> TxReceipt {
     let insert_res = insert(ie.clone())
         .await
         .map(|tx_id| Nat::from(tx_id))
@@ -615,7 +950,11 @@ async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
     if insert_res.is_err() {
         TXLOG.with(|t| {
             let mut tx_log = t.borrow_mut();
-            tx_log.ie_records.push_back(ie.clone());
+            if retry {
+                tx_log.ie_records.push_front(ie.clone());
+            } else {
+                tx_log.ie_records.push_back(ie.clone());
+            }
         });
     }
 
@@ -0,0 +1,222 @@
+use crate::audit;
+use crate::dip20;
+use crate::ledger::{_is_auth, BASE_REWARD};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+};
+use std::cell::RefCell;
+
+const ONE_DAY: u64 = 86_400_000_000_000;
+const MAX_BPS: u64 = 10_000;
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct EmissionConfig {
+    /// total supply no mint can go past
+    pub max_supply: Nat,
+    /// EMP minted as rewards per day (UTC) across all guilds, at the full rate
+    pub daily_budget: u64,
+    /// the rate halves every interval since emission started, in nanoseconds
+    pub halving_interval: u64,
+    /// floor the rate decays to, in basis points
+    pub min_rate_bps: u64,
+}
+
+impl Default for EmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_supply: Nat::from(1_000_000_000),
+            daily_budget: 1_000_000,
+            halving_interval: 365 * ONE_DAY,
+            min_rate_bps: 625,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Emission {
+    pub config: EmissionConfig,
+    /// time of the first reward, halvings count from here
+    pub started_at: Option<u64>,
+    /// day (since the epoch) `emitted_today` counts
+    pub day: u64,
+    pub emitted_today: u64,
+    pub total_emitted: u64,
+}
+
+impl Emission {
+    /// Current rate in basis points, halving every interval down to the floor
+    fn rate_bps(&self, now: u64) -> u64 {
+        let halvings = match self.started_at {
+            Some(started_at) => {
+                now.saturating_sub(started_at) / self.config.halving_interval.max(1)
+            }
+            None => 0,
+        };
+        (MAX_BPS >> halvings.min(63)).max(self.config.min_rate_bps)
+    }
+
+    fn scale(&self, amount: u64, now: u64) -> u64 {
+        amount * self.rate_bps(now) / MAX_BPS
+    }
+
+    fn emitted_on(&self, now: u64) -> u64 {
+        if self.day == now / ONE_DAY {
+            self.emitted_today
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct EmissionStatus {
+    pub rate_bps: u64,
+    /// default daily and work reward at the current rate
+    pub base_reward: u64,
+    pub daily_budget: u64,
+    pub remaining_today: u64,
+    pub total_emitted: u64,
+    pub total_supply: Nat,
+    pub max_supply: Nat,
+    pub next_halving_at: Option<u64>,
+}
+
+thread_local! {
+  static EMISSION: RefCell<Emission> = RefCell::new(Emission::default());
+}
+
+pub fn with<T, F: FnOnce(&Emission) -> T>(f: F) -> T {
+    EMISSION.with(|emission| f(&emission.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Emission) -> T>(f: F) -> T {
+    EMISSION.with(|emission| f(&mut emission.borrow_mut()))
+}
+
+/// Scale a reward by the current emission rate
+pub fn scale(reward: u64) -> u64 {
+    with(|emission| emission.scale(reward, ic::time()))
+}
+
+/// Err if minting `amount` would go past the max supply
+pub fn ensure_supply(amount: &Nat) -> Result<(), String> {
+    let max_supply = with(|emission| emission.config.max_supply.clone());
+    if dip20::total_supply() + amount.clone() > max_supply {
+        return Err("Max supply reached".to_string());
+    }
+    Ok(())
+}
+
+/// Take `amount` from today's reward budget. Every reward path calls this before
+/// minting, and errs without taking anything if the budget or supply can't cover it.
+pub fn spend(amount: u64) -> Result<(), String> {
+    ensure_supply(&Nat::from(amount))?;
+    let now = ic::time();
+    with_mut(|emission| {
+        let budget = emission.scale(emission.config.daily_budget, now);
+        let emitted = emission.emitted_on(now);
+        if emitted + amount > budget {
+            return Err("today's reward budget is used up, try again after 00:00 UTC".to_string());
+        }
+        emission.started_at.get_or_insert(now);
+        emission.day = now / ONE_DAY;
+        emission.emitted_today = emitted + amount;
+        emission.total_emitted += amount;
+        Ok(())
+    })
+}
+
+/// Get the current emission rate and what's left of today's reward budget
+#[query]
+#[candid_method(query)]
+fn emission_status() -> EmissionStatus {
+    let now = ic::time();
+    with(|emission| {
+        let daily_budget = emission.scale(emission.config.daily_budget, now);
+        let rate_bps = emission.rate_bps(now);
+        let next_halving_at = match emission.started_at {
+            Some(started_at) if rate_bps > emission.config.min_rate_bps => {
+                let interval = emission.config.halving_interval.max(1);
+                Some(started_at + ((now - started_at) / interval + 1) * interval)
+            }
+            _ => None,
+        };
+        EmissionStatus {
+            rate_bps,
+            base_reward: emission.scale(BASE_REWARD, now),
+            daily_budget,
+            remaining_today: daily_budget.saturating_sub(emission.emitted_on(now)),
+            total_emitted: emission.total_emitted,
+            total_supply: dip20::total_supply(),
+            max_supply: emission.config.max_supply.clone(),
+            next_halving_at,
+        }
+    })
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_emission_config(config: EmissionConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res = if config.halving_interval == 0 {
+        Err("Halving interval must be more than 0".to_string())
+    } else if config.min_rate_bps > MAX_BPS {
+        Err(format!(
+            "Min rate can't be more than {} basis points",
+            MAX_BPS
+        ))
+    } else {
+        with_mut(|emission| emission.config = config);
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_emission_config", args, &res);
+    res
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_emission_config() -> EmissionConfig {
+    with(|emission| emission.config.clone())
+}
+
+// END CUSTODIAN METHODS //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started() -> Emission {
+        Emission {
+            started_at: Some(0),
+            ..Emission::default()
+        }
+    }
+
+    #[test]
+    fn full_rate_until_started() {
+        assert_eq!(Emission::default().rate_bps(100 * 365 * ONE_DAY), MAX_BPS);
+    }
+
+    #[test]
+    fn rate_halves_every_interval_down_to_the_floor() {
+        let emission = started();
+        let year = emission.config.halving_interval;
+        assert_eq!(emission.rate_bps(year - 1), 10_000);
+        assert_eq!(emission.rate_bps(year), 5_000);
+        assert_eq!(emission.rate_bps(2 * year), 2_500);
+        assert_eq!(emission.rate_bps(10 * year), 625);
+        assert_eq!(emission.rate_bps(u64::MAX), 625);
+    }
+
+    #[test]
+    fn scale_applies_the_rate() {
+        let emission = started();
+        let year = emission.config.halving_interval;
+        assert_eq!(emission.scale(1_000, 0), 1_000);
+        assert_eq!(emission.scale(1_000, year), 500);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::audit;
+use crate::http;
+use crate::ledger::_is_auth;
+use crate::lock;
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+const ONE_MINUTE: u64 = 60_000_000_000;
+/// Longest wait between two attempts of a delivery
+const MAX_BACKOFF: u64 = 60 * ONE_MINUTE;
+
+/// Max deliveries attempted per heartbeat
+const DELIVERIES_PER_TICK: usize = 4;
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    StreakMilestone,
+    ShopPurchase,
+    LargeTransfer,
+    Registration,
+    LotteryDraw,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct EventConfig {
+    /// webhook urls to post to, per event type
+    pub routes: HashMap<EventKind, Vec<String>>,
+    /// daily streaks that trigger an announcement
+    pub streak_milestones: Vec<u64>,
+    /// minimum transfer amount that triggers an announcement
+    pub large_transfer_threshold: Nat,
+    /// max messages sent to a single webhook per minute
+    pub max_per_minute: u64,
+    /// deliveries are dropped after this many failed attempts
+    pub max_attempts: u32,
+    /// events are dropped while the outbox is full
+    pub outbox_limit: usize,
+    /// https endpoint deliveries are posted to instead of the webhook, which forwards
+    /// each idempotency key once. Every replica makes the outcall, so without a relay
+    /// discord receives each message once per replica.
+    pub relay: Option<String>,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        Self {
+            routes: HashMap::new(),
+            streak_milestones: vec![7, 30, 100, 365],
+            large_transfer_threshold: Nat::from(10_000),
+            max_per_minute: 5,
+            max_attempts: 5,
+            outbox_limit: 500,
+            relay: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Delivery {
+    pub id: u64,
+    pub kind: EventKind,
+    pub url: String,
+    pub content: String,
+    pub created_at: u64,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Outbox {
+    pub next_id: u64,
+    pub pending: VecDeque<Delivery>,
+    /// recent send timestamps per webhook, used for rate limiting
+    pub sent: HashMap<String, VecDeque<u64>>,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Events {
+    pub config: EventConfig,
+    pub outbox: Outbox,
+}
+
+thread_local! {
+  static EVENTS: RefCell<Events> = RefCell::new(Events::default());
+  static PROCESSING: RefCell<Option<u64>> = RefCell::new(None);
+}
+
+pub fn with<T, F: FnOnce(&Events) -> T>(f: F) -> T {
+    EVENTS.with(|events| f(&events.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Events) -> T>(f: F) -> T {
+    EVENTS.with(|events| f(&mut events.borrow_mut()))
+}
+
+/// Queue an announcement for every webhook routed to `kind`
+pub fn publish(kind: EventKind, content: String) {
+    with_mut(|events| {
+        let urls = match events.config.routes.get(&kind) {
+            Some(urls) => urls.clone(),
+            None => return,
+        };
+        let now = ic::time();
+        for url in urls {
+            if events.outbox.pending.len() >= events.config.outbox_limit {
+                ic::print(format!("event outbox full, dropping {:?} event", kind));
+                return;
+            }
+            let id = events.outbox.next_id;
+            events.outbox.next_id += 1;
+            events.outbox.pending.push_back(Delivery {
+                id,
+                kind,
+                url,
+                content: content.clone(),
+                created_at: now,
+                attempts: 0,
+                next_attempt: now,
+                last_error: None,
+            });
+        }
+    })
+}
+
+pub fn is_streak_milestone(streak: u64) -> bool {
+    with(|events| events.config.streak_milestones.contains(&streak))
+}
+
+pub fn is_large_transfer(amount: &Nat) -> bool {
+    with(|events| *amount >= events.config.large_transfer_threshold)
+}
+
+/// Pull due deliveries out of the outbox, respecting each webhook's rate limit
+fn take_due(now: u64) -> Vec<Delivery> {
+    with_mut(|events| {
+        let max_per_minute = events.config.max_per_minute as usize;
+        let outbox = &mut events.outbox;
+
+        for sent in outbox.sent.values_mut() {
+            while sent.front().map_or(false, |t| now - t >= ONE_MINUTE) {
+                sent.pop_front();
+            }
+        }
+        outbox.sent.retain(|_, sent| !sent.is_empty());
+
+        let mut due = Vec::new();
+        let mut remaining = VecDeque::new();
+        while let Some(delivery) = outbox.pending.pop_front() {
+            let sent = outbox.sent.entry(delivery.url.clone()).or_default();
+            if due.len() < DELIVERIES_PER_TICK
+                && delivery.next_attempt <= now
+                && sent.len() < max_per_minute
+            {
+                sent.push_back(now);
+                due.push(delivery);
+            } else {
+                remaining.push_back(delivery);
+            }
+        }
+        outbox.pending = remaining;
+
+        due
+    })
+}
+
+async fn deliver(delivery: &Delivery) -> Result<(), String> {
+    let payload = serde_json::json!({
+        "content": delivery.content,
+        "allowed_mentions": { "parse": [] },
+    });
+    let res = match with(|events| events.config.relay.clone()) {
+        Some(relay) => {
+            let body = serde_json::json!({ "webhook": delivery.url, "payload": payload });
+            // the same key on every retry, so the relay forwards a delivery once
+            let key = format!("{}-{}", ic::id(), delivery.id);
+            http::post_json(relay, body.to_string().into_bytes(), Some(key)).await?
+        }
+        None => {
+            http::post_json(delivery.url.clone(), payload.to_string().into_bytes(), None).await?
+        }
+    };
+
+    if res.status >= Nat::from(200) && res.status < Nat::from(300) {
+        Ok(())
+    } else {
+        Err(format!(
+            "webhook responded with {}: {}",
+            res.status,
+            String::from_utf8_lossy(&res.body)
+        ))
+    }
+}
+
+/// Wait before the next attempt of a delivery, doubling per failed attempt
+fn backoff(attempts: u32) -> u64 {
+    ONE_MINUTE
+        .saturating_mul(2u64.saturating_pow(attempts))
+        .min(MAX_BACKOFF)
+}
+
+/// Send due deliveries, requeueing failures with an exponential backoff.
+/// Called from the canister heartbeat.
+pub async fn process_outbox() {
+    if !lock::acquire(&PROCESSING) {
+        return;
+    }
+
+    for mut delivery in take_due(ic::time()) {
+        if let Err(e) = deliver(&delivery).await {
+            delivery.attempts += 1;
+            delivery.last_error = Some(e);
+
+            with_mut(|events| {
+                if delivery.attempts >= events.config.max_attempts {
+                    ic::print(format!(
+                        "dropping webhook delivery {} after {} attempts",
+                        delivery.id, delivery.attempts
+                    ));
+                    return;
+                }
+                delivery.next_attempt = ic::time() + backoff(delivery.attempts);
+                events.outbox.pending.push_back(delivery);
+            });
+        }
+    }
+
+    lock::release(&PROCESSING);
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_event_config(config: EventConfig) {
+    audit::record_ok("set_event_config", String::new());
+    with_mut(|events| events.config = config);
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_event_config() -> EventConfig {
+    with(|events| events.config.clone())
+}
+
+/// Set the webhooks an event type is announced to
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_webhooks(kind: EventKind, urls: Vec<String>) -> Result<(), String> {
+    // webhook urls are secrets, only the count is audited
+    let args = format!("{:?}: {} webhooks", kind, urls.len());
+    let res = if urls
+        .iter()
+        .any(|url| !url.starts_with("https://discord.com/api/webhooks/"))
+    {
+        Err("Invalid discord webhook url".to_string())
+    } else {
+        with_mut(|events| {
+            if urls.is_empty() {
+                events.config.routes.remove(&kind);
+            } else {
+                events.config.routes.insert(kind, urls);
+            }
+        });
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_webhooks", args, &res);
+    res
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_outbox() -> Vec<Delivery> {
+    with(|events| events.outbox.pending.iter().cloned().collect())
+}
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn clear_outbox() -> u64 {
+    audit::record_ok("clear_outbox", String::new());
+    with_mut(|events| {
+        let cleared = events.outbox.pending.len() as u64;
+        events.outbox.pending.clear();
+        cleared
+    })
+}
+
+// END CUSTODIAN METHODS //
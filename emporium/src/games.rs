@@ -0,0 +1,345 @@
+use crate::accounts;
+use crate::audit;
+use crate::dip20::{_history_inc, _spend_allowance, _transfer, balance_of, queue_cap_record};
+use crate::ledger::{self, _is_auth};
+use crate::maintenance::{self, Subsystem};
+use crate::metrics;
+use crate::moderation;
+use crate::random;
+use crate::rate_limit;
+use crate::tip::ensure_spendable;
+use crate::treasury::{self, Category};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// Results kept for verification, older ones are only in cap
+const MAX_RESULTS: usize = 10_000;
+const MAX_PAGE_SIZE: usize = 100;
+/// Highest dice target, the roll is 1 to 100
+const MAX_DICE_TARGET: u64 = 95;
+const MAX_BPS: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq)]
+pub enum Side {
+    Heads,
+    Tails,
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum Bet {
+    Coinflip(Side),
+    /// win if the roll is at most the target
+    Dice(u64),
+}
+
+impl Bet {
+    fn game(&self) -> &'static str {
+        match self {
+            Bet::Coinflip(_) => "coinflip",
+            Bet::Dice(_) => "dice",
+        }
+    }
+
+    /// Chance of winning, in percent
+    fn win_chance(&self) -> u64 {
+        match self {
+            Bet::Coinflip(_) => 50,
+            Bet::Dice(target) => *target,
+        }
+    }
+
+    /// Outcome from the random number: 0 (heads) or 1 (tails) for a coinflip, 1 to 100 for dice
+    fn roll(&self, n: u64) -> u64 {
+        match self {
+            Bet::Coinflip(_) => n % 2,
+            Bet::Dice(_) => n % 100 + 1,
+        }
+    }
+
+    fn wins(&self, roll: u64) -> bool {
+        match self {
+            Bet::Coinflip(Side::Heads) => roll == 0,
+            Bet::Coinflip(Side::Tails) => roll == 1,
+            Bet::Dice(target) => roll <= *target,
+        }
+    }
+
+    /// Fair payout for the win chance, minus the house edge
+    fn payout(&self, wager: &Nat, house_edge_bps: u64) -> Nat {
+        wager.clone() * Nat::from(100 * (MAX_BPS - house_edge_bps))
+            / Nat::from(self.win_chance() * MAX_BPS)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct GameConfig {
+    /// cut of fair payouts kept by the house, in basis points
+    pub house_edge_bps: u64,
+    /// max wager as a share of the house bankroll, in basis points
+    pub max_bet_bps: u64,
+    pub min_bet: Nat,
+    /// where lost wagers go, the treasury if not set
+    pub loss_to: Option<Principal>,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            house_edge_bps: 100,
+            max_bet_bps: 100,
+            min_bet: Nat::from(10),
+            loss_to: None,
+        }
+    }
+}
+
+/// A played game, with the `raw_rand` bytes it was decided by. The roll is the
+/// first 8 bytes read as a big endian u64, mod 2 for a coinflip and mod 100 plus
+/// one for dice, so anyone can check the outcome.
+#[derive(Clone, Deserialize, CandidType)]
+pub struct GameResult {
+    pub id: u64,
+    pub bet: Bet,
+    pub discord_id: String,
+    pub player: Principal,
+    pub wager: Nat,
+    pub randomness: Vec<u8>,
+    pub roll: u64,
+    pub won: bool,
+    pub payout: Nat,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Games {
+    pub config: GameConfig,
+    pub next_id: u64,
+    pub results: VecDeque<GameResult>,
+}
+
+thread_local! {
+  static GAMES: RefCell<Games> = RefCell::new(Games::default());
+}
+
+pub fn with<T, F: FnOnce(&Games) -> T>(f: F) -> T {
+    GAMES.with(|games| f(&games.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Games) -> T>(f: F) -> T {
+    GAMES.with(|games| f(&mut games.borrow_mut()))
+}
+
+struct Wager {
+    player: Principal,
+    payout: Nat,
+    delegated: bool,
+}
+
+/// Check the bet against the config and bankroll, and that the player can pay the wager
+fn validate(caller: Principal, discord_id: &str, wager: &Nat, bet: &Bet) -> Result<Wager, String> {
+    if let Bet::Dice(target) = bet {
+        if *target == 0 || *target > MAX_DICE_TARGET {
+            return Err(format!("Target must be between 1 and {}", MAX_DICE_TARGET));
+        }
+    }
+    let config = with(|games| games.config.clone());
+    if *wager < config.min_bet {
+        return Err(format!("Min bet is `{} EMP`", config.min_bet));
+    }
+    let player = ledger::with(|ledger| ledger.users.get(discord_id).map(|u| u.principal))
+        .ok_or_else(|| format!("<@{}>, you are not registered", discord_id))?;
+
+    let bankroll = balance_of(accounts::house());
+    let max_bet = bankroll.clone() * Nat::from(config.max_bet_bps) / Nat::from(MAX_BPS);
+    if *wager > max_bet {
+        return Err(format!("Max bet is `{} EMP`", max_bet));
+    }
+    let payout = bet.payout(wager, config.house_edge_bps);
+    if payout > bankroll + wager.clone() {
+        return Err("The house can't cover this bet".to_string());
+    }
+
+    let delegated = ensure_spendable(caller, discord_id, player, wager)?;
+    Ok(Wager {
+        player,
+        payout,
+        delegated,
+    })
+}
+
+async fn play(discord_id: String, wager: Nat, bet: Bet) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Games)?;
+    let caller = ic::caller();
+    rate_limit::check(bet.game(), caller, Some(&discord_id))?;
+    moderation::ensure_allowed(&discord_id)?;
+
+    // checked before and after drawing randomness, balances can change in between.
+    // the bet is settled in the same message as the second check, so it can't be
+    // withdrawn once the randomness is known
+    validate(caller, &discord_id, &wager, &bet)?;
+    let randomness = random::bytes().await.map_err(|e| {
+        ic::print(format!("raw_rand failed: {}", e));
+        "Couldn't draw randomness, try again".to_string()
+    })?;
+    let Wager {
+        player,
+        payout,
+        delegated,
+    } = validate(caller, &discord_id, &wager, &bet)?;
+
+    let roll = bet.roll(random::to_u64(&randomness));
+    let won = bet.wins(roll);
+    let house = accounts::house();
+    let loss_to = with(|games| games.config.loss_to);
+
+    _transfer(player, house, wager.clone());
+    if delegated {
+        _spend_allowance(player, caller, wager.clone());
+    }
+    let payout = if won {
+        _transfer(house, player, payout.clone());
+        payout
+    } else {
+        match loss_to {
+            Some(loss_to) => _transfer(house, loss_to, wager.clone()),
+            None => treasury::deposit(house, wager.clone(), Category::GameLosses),
+        }
+        Nat::from(0)
+    };
+    _history_inc();
+
+    let now = ic::time();
+    let result = with_mut(|games| {
+        let result = GameResult {
+            id: games.next_id,
+            bet: bet.clone(),
+            discord_id: discord_id.clone(),
+            player,
+            wager: wager.clone(),
+            randomness: randomness.clone(),
+            roll,
+            won,
+            payout: payout.clone(),
+            timestamp: now,
+        };
+        games.next_id += 1;
+        games.results.push_back(result.clone());
+        if games.results.len() > MAX_RESULTS {
+            games.results.pop_front();
+        }
+        result
+    });
+
+    queue_cap_record(IndefiniteEvent {
+        caller,
+        operation: bet.game().to_string(),
+        details: vec![
+            ("game_id".to_string(), DetailValue::U64(result.id)),
+            ("player".to_string(), DetailValue::from(player)),
+            (
+                "discord_id".to_string(),
+                DetailValue::Text(discord_id.clone()),
+            ),
+            ("wager".to_string(), DetailValue::from(wager.clone())),
+            ("payout".to_string(), DetailValue::from(payout.clone())),
+            ("roll".to_string(), DetailValue::U64(roll)),
+            (
+                "randomness".to_string(),
+                DetailValue::Text(random::to_hex(&randomness)),
+            ),
+            ("timestamp".to_string(), DetailValue::U64(now)),
+        ],
+    });
+
+    let outcome = match bet {
+        Bet::Coinflip(_) => format!(":coin: **{}**", if roll == 0 { "Heads" } else { "Tails" }),
+        Bet::Dice(target) => format!(":game_die: Rolled **{}** (needed {} or less)", roll, target),
+    };
+    Ok(if won {
+        format!(
+            "<@{}> {}, you won `{} EMP`! (game #{})",
+            discord_id, outcome, payout, result.id
+        )
+    } else {
+        format!(
+            "<@{}> {}, you lost `{} EMP` (game #{})",
+            discord_id, outcome, wager, result.id
+        )
+    })
+}
+
+/// Bet on a coinflip, paying twice the wager minus the house edge. Like a tip,
+/// the bot needs an allowance from the player covering the wager.
+#[update]
+#[candid_method]
+async fn coinflip(discord_id: String, wager: Nat, side: Side) -> Result<String, String> {
+    let res = play(discord_id, wager, Bet::Coinflip(side)).await;
+    metrics::observe("coinflip", &res);
+    res
+}
+
+/// Bet on a 1 to 100 roll being at most `target`, paying the wager times
+/// 100 / target minus the house edge
+#[update]
+#[candid_method]
+async fn dice(discord_id: String, wager: Nat, target: u64) -> Result<String, String> {
+    let res = play(discord_id, wager, Bet::Dice(target)).await;
+    metrics::observe("dice", &res);
+    res
+}
+
+/// Get the latest game results, newest first, optionally for one user
+#[query]
+#[candid_method(query)]
+fn get_game_results(discord_id: Option<String>, limit: u64) -> Vec<GameResult> {
+    with(|games| {
+        games
+            .results
+            .iter()
+            .rev()
+            .filter(|r| discord_id.as_ref().map_or(true, |id| &r.discord_id == id))
+            .take((limit as usize).min(MAX_PAGE_SIZE))
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_game_config() -> GameConfig {
+    with(|games| games.config.clone())
+}
+
+/// Get the house principal and its balance, custodians fund the bankroll by
+/// transferring EMP to it
+#[query]
+#[candid_method(query)]
+fn house_bankroll() -> (Principal, Nat) {
+    let house = accounts::house();
+    (house, balance_of(house))
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_game_config(config: GameConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res = if config.house_edge_bps >= MAX_BPS || config.max_bet_bps > MAX_BPS {
+        Err(format!("Basis points must be below {}", MAX_BPS))
+    } else {
+        with_mut(|games| games.config = config);
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_game_config", args, &res);
+    res
+}
+
+// END CUSTODIAN METHODS //
@@ -0,0 +1,283 @@
+use crate::dip20::{balance_of, BALANCES, STATS};
+use crate::ledger;
+use crate::metrics;
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// How often the certified routes are rebuilt from the heartbeat
+const CERTIFY_INTERVAL: u64 = 60_000_000_000;
+const LEADERBOARD_SIZE: usize = 100;
+
+/// Routes served from the certified cache
+const CERTIFIED_ROUTES: [&str; 3] = ["/stats", "/token", "/leaderboard"];
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct HttpGatewayResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Certified {
+    last_update: u64,
+    tree: RbTree<String, Hash>,
+    bodies: HashMap<String, Vec<u8>>,
+}
+
+thread_local! {
+  static CERTIFIED: RefCell<Certified> = RefCell::new(Certified::default());
+}
+
+fn json_body(value: Value) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+/// A `Nat` as a json number, or a string of digits if it doesn't fit a u64.
+/// candid's `Display` adds `_` separators, so the inner integer is formatted.
+fn nat_json(nat: &Nat) -> Value {
+    let digits = nat.0.to_string();
+    match digits.parse::<u64>() {
+        Ok(n) => json!(n),
+        Err(_) => Value::String(digits),
+    }
+}
+
+fn stats_json() -> Value {
+    let stats = STATS.with(|s| s.borrow().clone());
+    let holders = BALANCES.with(|b| b.borrow().len());
+    let total_users = ledger::with(|ledger| ledger.total_users);
+
+    json!({
+        "name": stats.name,
+        "symbol": stats.symbol,
+        "total_supply": nat_json(&stats.total_supply),
+        "holders": holders,
+        "total_users": total_users,
+        "history_size": stats.history_size,
+    })
+}
+
+/// Mirrors `getTokenInfo`
+fn token_json() -> Value {
+    let stats = STATS.with(|s| s.borrow().clone());
+    let holders = BALANCES.with(|b| b.borrow().len());
+
+    json!({
+        "name": stats.name,
+        "symbol": stats.symbol,
+        "logo": stats.logo,
+        "decimals": stats.decimals,
+        "total_supply": nat_json(&stats.total_supply),
+        "owner": stats.owner.to_text(),
+        "fee": nat_json(&stats.fee),
+        "fee_to": stats.fee_to.to_text(),
+        "holders": holders,
+        "history_size": stats.history_size,
+        "deploy_time": stats.deploy_time,
+        "cycles": ic::balance(),
+    })
+}
+
+/// Public view of a user, mirrors `user_balance`
+fn user_json(user: &ledger::User) -> Value {
+    json!({
+        "discord_id": user.discord_id,
+        "balance": nat_json(&balance_of(user.principal)),
+        "total_rewards": user.total_rewards,
+        "streak_freezes": user.streak_freezes,
+        "guilds": user
+            .guilds
+            .iter()
+            .map(|(guild_id, stats)| {
+                (
+                    guild_id.clone(),
+                    json!({
+                        "total_rewards": stats.total_rewards,
+                        "daily_streak": stats.daily.streak,
+                        "work_streak": stats.work.streak,
+                        "joined_at": stats.joined_at,
+                    }),
+                )
+            })
+            .collect::<serde_json::Map<String, Value>>(),
+    })
+}
+
+fn guild_leaderboard_json(guild_id: &str) -> Value {
+    Value::Array(
+        ledger::with(|ledger| ledger.guild_leaderboard(guild_id, LEADERBOARD_SIZE))
+            .into_iter()
+            .enumerate()
+            .map(|(i, (discord_id, total_rewards))| {
+                json!({
+                    "rank": i + 1,
+                    "discord_id": discord_id,
+                    "total_rewards": total_rewards,
+                })
+            })
+            .collect(),
+    )
+}
+
+fn leaderboard_json() -> Value {
+    let mut users: Vec<(Nat, Value)> = ledger::with(|ledger| {
+        ledger
+            .users
+            .values()
+            .map(|user| (balance_of(user.principal), user_json(user)))
+            .collect()
+    });
+    users.sort_by(|a, b| b.0.cmp(&a.0));
+
+    Value::Array(
+        users
+            .into_iter()
+            .take(LEADERBOARD_SIZE)
+            .enumerate()
+            .map(|(i, (_, mut user))| {
+                user["rank"] = json!(i + 1);
+                user
+            })
+            .collect(),
+    )
+}
+
+fn route_body(path: &str) -> Option<Vec<u8>> {
+    match path {
+        "/stats" => Some(json_body(stats_json())),
+        "/token" => Some(json_body(token_json())),
+        "/leaderboard" => Some(json_body(leaderboard_json())),
+        _ => None,
+    }
+}
+
+/// Rebuild the certified routes and update the canister's certified data.
+/// Called from the heartbeat, at most once per `CERTIFY_INTERVAL`.
+pub fn certify() {
+    let now = ic::time();
+    if CERTIFIED.with(|c| now - c.borrow().last_update < CERTIFY_INTERVAL) {
+        return;
+    }
+
+    CERTIFIED.with(|c| {
+        let mut certified = c.borrow_mut();
+        for path in CERTIFIED_ROUTES {
+            let body = route_body(path).unwrap();
+            certified
+                .tree
+                .insert(path.to_string(), Sha256::digest(&body).into());
+            certified.bodies.insert(path.to_string(), body);
+        }
+        certified.last_update = now;
+
+        ic_cdk::api::set_certified_data(&labeled_hash(b"http_assets", &certified.tree.root_hash()));
+    });
+}
+
+fn certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().ok()?;
+    CERTIFIED
+        .with(|c| {
+            let certified = c.borrow();
+            let tree = labeled(b"http_assets", certified.tree.witness(path.as_bytes()));
+            tree.serialize(&mut serializer)
+        })
+        .ok()?;
+
+    Some((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            base64::encode(&certificate),
+            base64::encode(&serializer.into_inner())
+        ),
+    ))
+}
+
+fn response(status_code: u16, body: Vec<u8>) -> HttpGatewayResponse {
+    HttpGatewayResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+    }
+}
+
+fn error(status_code: u16, message: &str) -> HttpGatewayResponse {
+    response(status_code, json_body(json!({ "error": message })))
+}
+
+/// Serve public json stats to the http gateway.
+///
+/// `/stats`, `/token` and `/leaderboard` are certified, `/user/<discord_id>`,
+/// `/leaderboard/<guild_id>` and the prometheus `/metrics` are served live.
+/// Never exposes discord auth tokens.
+#[query]
+#[candid_method(query)]
+fn http_request(req: HttpRequest) -> HttpGatewayResponse {
+    if req.method != "GET" {
+        return error(405, "Method not allowed");
+    }
+
+    let path = req.url.split('?').next().unwrap_or("/");
+
+    if path == "/metrics" {
+        let mut res = response(200, metrics::render().into_bytes());
+        res.headers[0].1 = "text/plain; version=0.0.4".to_string();
+        return res;
+    }
+
+    if let Some(guild_id) = path.strip_prefix("/leaderboard/") {
+        if !ledger::with(|ledger| ledger.guilds.contains_key(guild_id)) {
+            return error(404, "Guild not found");
+        }
+        return response(200, json_body(guild_leaderboard_json(guild_id)));
+    }
+
+    if let Some(discord_id) = path.strip_prefix("/user/") {
+        return match ledger::with(|ledger| ledger.users.get(discord_id).map(user_json)) {
+            Some(user) => response(200, json_body(user)),
+            None => error(404, "User not found"),
+        };
+    }
+
+    let cached = CERTIFIED.with(|c| c.borrow().bodies.get(path).cloned());
+    match cached {
+        Some(body) => {
+            let mut res = response(200, body);
+            if let Some(header) = certificate_header(path) {
+                res.headers.push(header);
+            }
+            res
+        }
+        // not certified yet, serve live
+        None => match route_body(path) {
+            Some(body) => response(200, body),
+            None => error(404, "Not found"),
+        },
+    }
+}
@@ -0,0 +1,433 @@
+use crate::accounts;
+use crate::audit;
+use crate::dip20::{_set_fee, BALANCES};
+use crate::ledger::{self, _is_auth, GuildConfig, StreakFreezeConfig};
+use crate::metrics;
+use crate::moderation;
+use crate::rate_limit;
+use crate::staking;
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+const ONE_DAY: u64 = 86_400_000_000_000;
+const MAX_BPS: u64 = 10_000;
+const MAX_DESCRIPTION_SIZE: usize = 1_000;
+const MAX_OPEN_PROPOSALS: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+/// Highest transfer fee a proposal can set, higher fees go through custodians
+const MAX_FEE: u64 = 100;
+
+/// Economy changes holders can vote on
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum Action {
+    SetFee(Nat),
+    /// reward config of an existing guild
+    SetGuildConfig {
+        guild_id: String,
+        config: GuildConfig,
+    },
+    /// price and limit of streak freezes in the shop
+    SetStreakFreezeConfig(StreakFreezeConfig),
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct GovernanceConfig {
+    pub voting_period: u64,
+    /// voting power needed to create a proposal
+    pub min_power_to_propose: Nat,
+    /// share of the snapshot's voting power that has to vote, in basis points
+    pub quorum_bps: u64,
+    /// share of the votes cast that has to be yes, in basis points
+    pub threshold_bps: u64,
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        Self {
+            voting_period: 3 * ONE_DAY,
+            min_power_to_propose: Nat::from(1_000),
+            quorum_bps: 1_000,
+            threshold_bps: 5_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq)]
+pub enum ProposalState {
+    Open,
+    Rejected(String),
+    Executed,
+    Failed(String),
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: Action,
+    pub description: String,
+    pub proposer: Principal,
+    pub created_at: u64,
+    pub ends_at: u64,
+    /// voting power at creation, balance plus staked EMP. Dropped once finalized.
+    pub snapshot: HashMap<Principal, Nat>,
+    pub total_power: Nat,
+    pub votes: HashMap<Principal, bool>,
+    pub yes: Nat,
+    pub no: Nat,
+    pub state: ProposalState,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Governance {
+    pub config: GovernanceConfig,
+    pub next_id: u64,
+    pub proposals: BTreeMap<u64, Proposal>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct ProposalInfo {
+    pub id: u64,
+    pub action: Action,
+    pub description: String,
+    pub proposer: Principal,
+    pub created_at: u64,
+    pub ends_at: u64,
+    pub total_power: Nat,
+    pub voters: u64,
+    pub yes: Nat,
+    pub no: Nat,
+    pub state: ProposalState,
+}
+
+impl From<&Proposal> for ProposalInfo {
+    fn from(proposal: &Proposal) -> Self {
+        Self {
+            id: proposal.id,
+            action: proposal.action.clone(),
+            description: proposal.description.clone(),
+            proposer: proposal.proposer,
+            created_at: proposal.created_at,
+            ends_at: proposal.ends_at,
+            total_power: proposal.total_power.clone(),
+            voters: proposal.votes.len() as u64,
+            yes: proposal.yes.clone(),
+            no: proposal.no.clone(),
+            state: proposal.state.clone(),
+        }
+    }
+}
+
+thread_local! {
+  static GOVERNANCE: RefCell<Governance> = RefCell::new(Governance::default());
+}
+
+pub fn with<T, F: FnOnce(&Governance) -> T>(f: F) -> T {
+    GOVERNANCE.with(|governance| f(&governance.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Governance) -> T>(f: F) -> T {
+    GOVERNANCE.with(|governance| f(&mut governance.borrow_mut()))
+}
+
+/// Voting power of every holder: balance plus staked EMP. The canister's
+/// reserved accounts don't vote.
+fn snapshot() -> HashMap<Principal, Nat> {
+    let mut power: HashMap<Principal, Nat> = BALANCES.with(|b| {
+        b.borrow()
+            .iter()
+            .filter(|(principal, balance)| !accounts::is_reserved(principal) && **balance > 0)
+            .map(|(principal, balance)| (*principal, balance.clone()))
+            .collect()
+    });
+    staking::with(|staking| {
+        for position in staking.positions.values() {
+            *power.entry(position.owner).or_default() += position.amount.clone();
+        }
+    });
+    power
+}
+
+fn validate(action: &Action) -> Result<(), String> {
+    match action {
+        Action::SetGuildConfig { guild_id, .. } => {
+            if !ledger::with(|ledger| ledger.guilds.contains_key(guild_id)) {
+                return Err("Unknown guild".to_string());
+            }
+        }
+        Action::SetStreakFreezeConfig(config) => {
+            if config.price == 0 {
+                return Err("Streak freezes can't be free".to_string());
+            }
+        }
+        Action::SetFee(fee) => {
+            if *fee > Nat::from(MAX_FEE) {
+                return Err(format!(
+                    "Proposals can set a fee of at most {} EMP",
+                    MAX_FEE
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn execute(action: Action) -> Result<(), String> {
+    validate(&action)?;
+    match action {
+        Action::SetFee(fee) => _set_fee(fee),
+        Action::SetGuildConfig { guild_id, config } => ledger::with_mut(|ledger| {
+            if let Some(guild) = ledger.guilds.get_mut(&guild_id) {
+                guild.config = config;
+            }
+        }),
+        Action::SetStreakFreezeConfig(config) => {
+            ledger::with_mut(|ledger| ledger.streak_freeze = config)
+        }
+    }
+    Ok(())
+}
+
+/// Propose an economy change for holders to vote on. The caller needs enough voting
+/// power, and voting power is snapshotted now.
+#[update]
+#[candid_method]
+fn governance_propose(action: Action, description: String) -> Result<u64, String> {
+    let res = _governance_propose(action, description);
+    metrics::observe("governance_propose", &res);
+    res
+}
+
+fn _governance_propose(action: Action, description: String) -> Result<u64, String> {
+    let caller = ic::caller();
+    rate_limit::check("governance_propose", caller, None)?;
+    moderation::ensure_allowed_principal(caller)?;
+
+    if description.len() > MAX_DESCRIPTION_SIZE {
+        return Err(format!(
+            "Description is limited to {} bytes",
+            MAX_DESCRIPTION_SIZE
+        ));
+    }
+    validate(&action)?;
+
+    let snapshot = snapshot();
+    let now = ic::time();
+    with_mut(|governance| {
+        let power = snapshot.get(&caller).cloned().unwrap_or_default();
+        if power < governance.config.min_power_to_propose {
+            return Err(format!(
+                "Proposing requires `{} EMP` of voting power",
+                governance.config.min_power_to_propose
+            ));
+        }
+        let open = governance
+            .proposals
+            .values()
+            .filter(|p| p.state == ProposalState::Open)
+            .count();
+        if open >= MAX_OPEN_PROPOSALS {
+            return Err(format!("Max {} open proposals", MAX_OPEN_PROPOSALS));
+        }
+
+        let id = governance.next_id;
+        governance.next_id += 1;
+        governance.proposals.insert(
+            id,
+            Proposal {
+                id,
+                action,
+                description,
+                proposer: caller,
+                created_at: now,
+                ends_at: now + governance.config.voting_period,
+                total_power: snapshot
+                    .values()
+                    .fold(Nat::from(0), |total, power| total + power.clone()),
+                snapshot,
+                votes: HashMap::new(),
+                yes: Nat::from(0),
+                no: Nat::from(0),
+                state: ProposalState::Open,
+            },
+        );
+        Ok(id)
+    })
+}
+
+fn cast_vote(voter: Principal, id: u64, yes: bool) -> Result<Nat, String> {
+    let now = ic::time();
+    with_mut(|governance| {
+        let proposal = governance
+            .proposals
+            .get_mut(&id)
+            .ok_or("Proposal not found")?;
+        if proposal.state != ProposalState::Open || proposal.ends_at <= now {
+            return Err("Voting on this proposal has ended".to_string());
+        }
+        if proposal.votes.contains_key(&voter) {
+            return Err("Already voted on this proposal".to_string());
+        }
+        let power = proposal
+            .snapshot
+            .get(&voter)
+            .cloned()
+            .filter(|power| *power > 0)
+            .ok_or("No voting power at the proposal's snapshot")?;
+
+        proposal.votes.insert(voter, yes);
+        if yes {
+            proposal.yes += power.clone();
+        } else {
+            proposal.no += power.clone();
+        }
+        Ok(power)
+    })
+}
+
+/// Vote on a proposal with the caller's voting power at its snapshot
+#[update]
+#[candid_method]
+fn governance_vote(id: u64, yes: bool) -> Result<Nat, String> {
+    let caller = ic::caller();
+    let res = rate_limit::check("governance_vote", caller, None)
+        .and_then(|_| moderation::ensure_allowed_principal(caller))
+        .and_then(|_| cast_vote(caller, id, yes));
+    metrics::observe("governance_vote", &res);
+    res
+}
+
+/// Vote on a proposal from discord, with the voting power of the user's principal
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn governance_vote_discord(discord_id: String, id: u64, yes: bool) -> Result<String, String> {
+    let res = _governance_vote_discord(discord_id, id, yes);
+    metrics::observe("governance_vote_discord", &res);
+    res
+}
+
+fn _governance_vote_discord(discord_id: String, id: u64, yes: bool) -> Result<String, String> {
+    rate_limit::check("governance_vote", ic::caller(), Some(&discord_id))?;
+    moderation::ensure_allowed(&discord_id)?;
+    let principal = ledger::with(|ledger| ledger.users.get(&discord_id).map(|u| u.principal))
+        .ok_or("Unregistered user")?;
+    let power = cast_vote(principal, id, yes).map_err(|e| format!("<@{}>, {}", discord_id, e))?;
+    Ok(format!(
+        "<@{}> voted **{}** on proposal #{} with `{} EMP` of voting power :ballot_box:",
+        discord_id,
+        if yes { "yes" } else { "no" },
+        id,
+        power
+    ))
+}
+
+/// Tally proposals whose voting period ended, executing the ones that passed.
+/// Called from the canister heartbeat.
+pub fn finalize() {
+    let now = ic::time();
+    let ended: Vec<u64> = with(|governance| {
+        governance
+            .proposals
+            .values()
+            .filter(|p| p.state == ProposalState::Open && p.ends_at <= now)
+            .map(|p| p.id)
+            .collect()
+    });
+
+    for id in ended {
+        let (action, outcome) = with_mut(|governance| {
+            let config = governance.config.clone();
+            let proposal = governance.proposals.get_mut(&id).unwrap();
+            proposal.snapshot.clear();
+
+            let votes = proposal.yes.clone() + proposal.no.clone();
+            let outcome = if votes.clone() * Nat::from(MAX_BPS)
+                < proposal.total_power.clone() * Nat::from(config.quorum_bps)
+            {
+                Err("Quorum not reached".to_string())
+            } else if proposal.yes.clone() * Nat::from(MAX_BPS)
+                <= votes * Nat::from(config.threshold_bps)
+            {
+                Err("Threshold not reached".to_string())
+            } else {
+                Ok(())
+            };
+            (proposal.action.clone(), outcome)
+        });
+
+        let state = match outcome {
+            Err(reason) => ProposalState::Rejected(reason),
+            Ok(()) => {
+                let args = format!("proposal: {}, {:?}", id, action);
+                let res = execute(action);
+                audit::record(ic::id(), "governance_execute", args, &res);
+                match res {
+                    Ok(()) => ProposalState::Executed,
+                    Err(e) => ProposalState::Failed(e),
+                }
+            }
+        };
+        with_mut(|governance| governance.proposals.get_mut(&id).unwrap().state = state);
+    }
+}
+
+/// Get proposals, newest first
+#[query]
+#[candid_method(query)]
+fn get_governance_proposals(limit: u64) -> Vec<ProposalInfo> {
+    with(|governance| {
+        governance
+            .proposals
+            .values()
+            .rev()
+            .take((limit as usize).min(MAX_PAGE_SIZE))
+            .map(ProposalInfo::from)
+            .collect()
+    })
+}
+
+/// Get a principal's voting power at a proposal's snapshot and its vote if cast.
+/// Snapshots are dropped once a proposal is finalized.
+#[query]
+#[candid_method(query)]
+fn get_voting_power(id: u64, voter: Principal) -> Option<(Nat, Option<bool>)> {
+    with(|governance| {
+        governance.proposals.get(&id).map(|proposal| {
+            (
+                proposal.snapshot.get(&voter).cloned().unwrap_or_default(),
+                proposal.votes.get(&voter).copied(),
+            )
+        })
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_governance_config() -> GovernanceConfig {
+    with(|governance| governance.config.clone())
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_governance_config(config: GovernanceConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res = if config.quorum_bps > MAX_BPS || config.threshold_bps >= MAX_BPS {
+        Err(format!("Basis points must be below {}", MAX_BPS))
+    } else if config.voting_period == 0 {
+        Err("Voting period must be more than 0".to_string())
+    } else {
+        with_mut(|governance| governance.config = config);
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_governance_config", args, &res);
+    res
+}
+
+// END CUSTODIAN METHODS //
@@ -1,126 +1,166 @@
-use candid::{candid_method, CandidType, Nat, Principal};
-use ic_cdk;
-use ic_cdk_macros::{self, heartbeat, query, update};
-use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::collections::HashMap;
+use candid::{
+    candid_method,
+    types::{FuncMode, Function, Serializer, Type},
+    CandidType, Func, Nat, Principal,
+};
+use ic_cdk_macros::query;
+use serde::Deserialize;
 
-type Timestamp = u64;
-type Rate = f32;
+// Cycle costs for a 13 node application subnet
+const HTTP_BASE_FEE: u128 = (3_000_000 + 60_000 * 13) * 13;
+const HTTP_REQUEST_BYTE_FEE: u128 = 400 * 13;
+const HTTP_RESPONSE_BYTE_FEE: u128 = 800 * 13;
 
-#[derive(CandidType, Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+/// Webhook responses are tiny (discord replies with `204 No Content`)
+const MAX_RESPONSE_BYTES: u64 = 2048;
+
+// headers that differ between replicas and would break consensus
+const RESPONSE_HEADERS_BLACKLIST: [&str; 8] = [
+    "date",
+    "cf-ray",
+    "x-ratelimit-reset",
+    "x-ratelimit-reset-after",
+    "set-cookie",
+    "via",
+    "x-amz-cf-id",
+    "report-to",
+];
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq)]
 pub struct HttpHeader {
-  pub name: String,
-  pub value: String,
+    pub name: String,
+    pub value: String,
 }
 
-#[derive(Clone, Debug, PartialEq, CandidType, Eq, Hash, Serialize, Deserialize)]
+#[allow(non_camel_case_types, dead_code)]
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq)]
 pub enum HttpMethod {
-  GET,
+    get,
+    head,
+    post,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
-pub struct CanisterHttpRequestArgs {
-  pub url: String,
-  pub headers: Vec<HttpHeader>,
-  pub body: Option<Vec<u8>>,
-  pub http_method: HttpMethod,
-  pub transform_method_name: Option<String>,
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct HttpResponse {
+    pub status: Nat,
+    pub headers: Vec<HttpHeader>,
+    pub body: Vec<u8>,
 }
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct CanisterHttpResponsePayload {
-  pub status: u64,
-  pub headers: Vec<HttpHeader>,
-  pub body: Vec<u8>,
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct TransformArgs {
+    pub response: HttpResponse,
+    pub context: Vec<u8>,
 }
 
-thread_local! {
-    pub static RESPONSE_HEADERS_SANTIZATION: Vec<&'static str> = vec![
-        "x-mbx-uuid",
-        "x-mbx-used-weight",
-        "x-mbx-used-weight-1m",
-        "Date",
-        "Via",
-        "X-Amz-Cf-Id",
-    ];
+/// Reference to the `transform` query, typed the way the management canister expects
+#[derive(Clone, Deserialize, Debug)]
+pub struct TransformFunc(pub Func);
+
+impl CandidType for TransformFunc {
+    fn _ty() -> Type {
+        Type::Func(Function {
+            modes: vec![FuncMode::Query],
+            args: vec![TransformArgs::ty()],
+            rets: vec![HttpResponse::ty()],
+        })
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.0.idl_serialize(serializer)
+    }
 }
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct CaracterResponse {
-  pub id: u64,
-  pub name: String,
-  pub status: String,
-  pub species: String,
-  pub r#type: String,
-  pub gender: String,
-  pub image: String,
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct TransformContext {
+    pub function: TransformFunc,
+    pub context: Vec<u8>,
 }
-/*
-A function to call IC http_request function with a single minute range.
-This function is to be triggered by timer as jobs move to the tip of the queue.
- */
-pub async fn get(url: String) -> Result<CaracterResponse, String> {
-  // prepare system http_request call
-  let mut request_headers = vec![];
-  request_headers.insert(
-    0,
-    HttpHeader {
-      name: "Connection".to_string(),
-      value: "keep-alive".to_string(),
-    },
-  );
-
-  let request = CanisterHttpRequestArgs {
-    url,
-    http_method: HttpMethod::GET,
-    body: None,
-    transform_method_name: Some("sanitize_response".to_string()),
-    headers: request_headers,
-  };
-
-  let body = candid::utils::encode_one(&request).unwrap();
-
-  match ic_cdk::api::call::call_raw(
-    Principal::management_canister(),
-    "http_request",
-    &body[..],
-    0,
-  )
-  .await
-  {
-    Ok(result) => {
-      // decode the result
-      let decoded_result = candid::utils::decode_one(&result).unwrap();
-
-      // put the result to hashmap
-      ic_cdk::println!("{:?}", decoded_result);
-      return Ok(decoded_result);
-    }
-    Err((r, m)) => {
-      return Err(format!(
-        "The http_request resulted into error. RejectionCode: {r:?}, Error: {m}"
-      ));
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct CanisterHttpRequestArgs {
+    pub url: String,
+    pub max_response_bytes: Option<u64>,
+    pub method: HttpMethod,
+    pub headers: Vec<HttpHeader>,
+    pub body: Option<Vec<u8>>,
+    pub transform: Option<TransformContext>,
+}
+
+fn request_cycles(request: &CanisterHttpRequestArgs) -> u128 {
+    let request_bytes = request.url.len()
+        + request
+            .headers
+            .iter()
+            .map(|h| h.name.len() + h.value.len())
+            .sum::<usize>()
+        + request.body.as_ref().map(|b| b.len()).unwrap_or(0);
+
+    HTTP_BASE_FEE
+        + HTTP_REQUEST_BYTE_FEE * request_bytes as u128
+        + HTTP_RESPONSE_BYTE_FEE * request.max_response_bytes.unwrap_or(MAX_RESPONSE_BYTES) as u128
+}
+
+/// POST a json body to `url` via an https outcall, returning the (sanitized) response.
+///
+/// Every replica sends the request, so the endpoint receives it once per replica
+/// unless it deduplicates them by `idempotency_key`.
+pub async fn post_json(
+    url: String,
+    body: Vec<u8>,
+    idempotency_key: Option<String>,
+) -> Result<HttpResponse, String> {
+    let mut headers = vec![HttpHeader {
+        name: "Content-Type".to_string(),
+        value: "application/json".to_string(),
+    }];
+    if let Some(key) = idempotency_key {
+        headers.push(HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: key,
+        });
     }
-  }
+    let request = CanisterHttpRequestArgs {
+        url,
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::post,
+        headers,
+        body: Some(body),
+        transform: Some(TransformContext {
+            function: TransformFunc(Func {
+                principal: ic_cdk::id(),
+                method: "transform".to_string(),
+            }),
+            context: vec![],
+        }),
+    };
+
+    let cycles = request_cycles(&request);
+    let res: Result<(HttpResponse,), _> = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "http_request",
+        (request,),
+        cycles,
+    )
+    .await;
+
+    res.map(|(response,)| response).map_err(|(code, msg)| {
+        format!(
+            "The http_request resulted into error. RejectionCode: {:?}, Error: {}",
+            code, msg
+        )
+    })
 }
 
+/// Strip non deterministic headers so all replicas agree on the response
 #[query]
 #[candid_method(query)]
-#[export_name = "transform"]
-async fn sanitize_response(raw: CanisterHttpResponsePayload) -> CanisterHttpResponsePayload {
-  let mut sanitized = raw.clone();
-  RESPONSE_HEADERS_SANTIZATION.with(|response_headers_blacklist| {
-    let mut processed_headers = vec![];
-    for header in raw.headers.iter() {
-      if !response_headers_blacklist.contains(&header.name.as_str()) {
-        processed_headers.insert(0, header.clone());
-      }
-    }
-    sanitized.headers = processed_headers;
-  });
-  return sanitized;
+fn transform(raw: TransformArgs) -> HttpResponse {
+    let mut sanitized = raw.response;
+    sanitized.headers = sanitized
+        .headers
+        .into_iter()
+        .filter(|h| !RESPONSE_HEADERS_BLACKLIST.contains(&h.name.to_lowercase().as_str()))
+        .collect();
+    sanitized
 }
-
-#[cfg(any(target_arch = "wasm32", test))]
-fn main() {}
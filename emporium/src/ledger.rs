@@ -1,10 +1,22 @@
+use crate::token_proxy::_DIP721v2Proxy;
 use derive_new::new;
 use ic_kit::{
-    candid::{CandidType, Deserialize},
+    candid::{CandidType, Deserialize, Nat},
     ic, Principal,
 };
+use regex::Regex;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+
+/// Default daily and work reward for new guilds
+pub const BASE_REWARD: u64 = 100;
+/// How long claims are kept in the recent activity index
+pub const ACTIVITY_WINDOW: u64 = 24 * 3_600_000_000_000;
+/// Guild the stats of users from before per-guild economies were moved to, which
+/// the bot of the original server keeps claiming in
+pub const DEFAULT_GUILD: &str = "default";
+/// Rewards kept per user, for moderation clawbacks
+const REWARD_LOG_SIZE: usize = 1000;
 
 #[derive(Clone, Deserialize, CandidType)]
 pub struct StreakData {
@@ -28,14 +40,36 @@ pub struct AuthToken {
     pub expirey: u64,
 }
 
+/// A user's stats within a single guild
+#[derive(Clone, Deserialize, CandidType)]
+pub struct GuildStats {
+    pub daily: StreakData,
+    pub work: StreakData,
+    pub total_rewards: u64,
+    pub joined_at: u64,
+}
+
+impl GuildStats {
+    pub fn new(joined_at: u64) -> Self {
+        Self {
+            daily: StreakData::new(),
+            work: StreakData::new(),
+            total_rewards: 0,
+            joined_at,
+        }
+    }
+}
+
 #[derive(Clone, Deserialize, CandidType)]
 pub struct User {
     pub auth: Option<AuthToken>,
     pub discord_id: String,
     pub principal: Principal,
-    pub daily: StreakData,
-    pub work: StreakData,
+    /// rewards across all guilds
     pub total_rewards: u64,
+    pub guilds: HashMap<String, GuildStats>,
+    /// each freeze saves the daily streak for one missed day
+    pub streak_freezes: u64,
 }
 
 impl User {
@@ -44,19 +78,264 @@ impl User {
             auth,
             discord_id,
             principal,
-            daily: StreakData::new(),
-            work: StreakData::new(),
             total_rewards: 0,
+            guilds: HashMap::new(),
+            streak_freezes: 0,
+        }
+    }
+}
+
+/// Reward settings for a guild's economy
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct GuildConfig {
+    pub daily_reward: u64,
+    pub work_reward: u64,
+    /// cap on the streak bonus (streak squared) added to rewards
+    pub max_streak_bonus: u64,
+}
+
+impl Default for GuildConfig {
+    fn default() -> Self {
+        Self {
+            daily_reward: BASE_REWARD,
+            work_reward: BASE_REWARD,
+            max_streak_bonus: 10_000,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Guild {
+    pub name: String,
+    pub config: GuildConfig,
+    pub created_at: u64,
+    pub members: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct StreakFreezeConfig {
+    /// EMP per freeze, paid into the treasury
+    pub price: u64,
+    /// freezes a user can hold at once
+    pub max_held: u64,
+}
+
+impl Default for StreakFreezeConfig {
+    fn default() -> Self {
+        Self {
+            price: 500,
+            max_held: 3,
         }
     }
 }
 
+/// A `daily` or `work` claim, for the recent activity index
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Activity {
+    pub timestamp: u64,
+    pub guild_id: String,
+    pub discord_id: String,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Reward {
+    pub timestamp: u64,
+    pub amount: u64,
+    pub source: String,
+    /// guild whose stats count the reward, if any
+    pub guild_id: Option<String>,
+}
+
+/// `User` as stored before per-guild economies
+#[derive(Deserialize, CandidType)]
+pub struct LegacyUser {
+    pub auth: Option<AuthToken>,
+    pub discord_id: String,
+    pub principal: Principal,
+    pub daily: StreakData,
+    pub work: StreakData,
+    pub total_rewards: u64,
+}
+
+/// `Ledger` as stored before per-guild economies
+#[derive(Deserialize, CandidType)]
+pub struct LegacyLedger {
+    pub total_users: u64,
+    pub nft_canister: Option<Principal>,
+    pub users: HashMap<String, LegacyUser>,
+    pub principals: HashMap<Principal, String>,
+}
+
+impl LegacyLedger {
+    /// Move every user's streaks and rewards into the default guild
+    pub fn migrate(self, now: u64) -> Ledger {
+        let mut guilds = HashMap::new();
+        guilds.insert(
+            DEFAULT_GUILD.to_string(),
+            Guild {
+                name: "Default".to_string(),
+                config: GuildConfig::default(),
+                created_at: now,
+                members: self.users.len() as u64,
+            },
+        );
+        let users = self
+            .users
+            .into_iter()
+            .map(|(discord_id, legacy)| {
+                let stats = GuildStats {
+                    daily: legacy.daily,
+                    work: legacy.work,
+                    total_rewards: legacy.total_rewards,
+                    joined_at: now,
+                };
+                let user = User {
+                    auth: legacy.auth,
+                    discord_id: legacy.discord_id,
+                    principal: legacy.principal,
+                    total_rewards: legacy.total_rewards,
+                    guilds: std::iter::once((DEFAULT_GUILD.to_string(), stats)).collect(),
+                    streak_freezes: 0,
+                };
+                (discord_id, user)
+            })
+            .collect();
+
+        Ledger::new(
+            self.total_users,
+            self.nft_canister,
+            users,
+            self.principals,
+            HashMap::new(),
+            guilds,
+            StreakFreezeConfig::default(),
+            VecDeque::new(),
+            None,
+        )
+    }
+}
+
 #[derive(Clone, Deserialize, CandidType, new)]
 pub struct Ledger {
     pub total_users: u64,
     pub nft_canister: Option<Principal>,
     pub users: HashMap<String, User>,
     pub principals: HashMap<Principal, String>,
+    pub rewards: HashMap<String, VecDeque<Reward>>,
+    pub guilds: HashMap<String, Guild>,
+    pub streak_freeze: StreakFreezeConfig,
+    /// recent claims, oldest first
+    pub activity: VecDeque<Activity>,
+    /// next token id handed out for the NFT canister, see `next_token_id`. Reset
+    /// whenever `nft_canister` is set.
+    pub next_token_id: Option<Nat>,
+}
+
+impl Ledger {
+    /// Get a guild's config, a user and a copy of their stats in the guild (new
+    /// stats if they haven't joined it). Changes to the stats are kept with `save_member`.
+    pub fn member_mut(
+        &mut self,
+        guild_id: &str,
+        discord_id: &str,
+    ) -> Result<(GuildConfig, &mut User, GuildStats), String> {
+        let config = self
+            .guilds
+            .get(guild_id)
+            .ok_or("Unknown guild")?
+            .config
+            .clone();
+        let user = self.users.get_mut(discord_id).ok_or("Unregistered user")?;
+        let stats = user
+            .guilds
+            .get(guild_id)
+            .cloned()
+            .unwrap_or_else(|| GuildStats::new(ic::time()));
+
+        Ok((config, user, stats))
+    }
+
+    /// Store a user's stats in a guild, joining the guild on first use
+    pub fn save_member(&mut self, guild_id: &str, discord_id: &str, stats: GuildStats) {
+        let user = match self.users.get_mut(discord_id) {
+            Some(user) => user,
+            None => return,
+        };
+        if user.guilds.insert(guild_id.to_string(), stats).is_none() {
+            if let Some(guild) = self.guilds.get_mut(guild_id) {
+                guild.members += 1;
+            }
+        }
+    }
+
+    /// Record a claim in the recent activity index, dropping claims older than `ACTIVITY_WINDOW`
+    pub fn touch(&mut self, guild_id: &str, discord_id: &str, timestamp: u64) {
+        while self
+            .activity
+            .front()
+            .map_or(false, |a| timestamp - a.timestamp > ACTIVITY_WINDOW)
+        {
+            self.activity.pop_front();
+        }
+        self.activity.push_back(Activity {
+            timestamp,
+            guild_id: guild_id.to_string(),
+            discord_id: discord_id.to_string(),
+        });
+    }
+
+    /// Members of a guild who claimed since `since`, most recent first
+    pub fn active_since(&self, guild_id: &str, since: u64) -> Vec<String> {
+        let mut active: Vec<String> = vec![];
+        for a in self
+            .activity
+            .iter()
+            .rev()
+            .take_while(|a| a.timestamp >= since)
+            .filter(|a| a.guild_id == guild_id)
+        {
+            if !active.contains(&a.discord_id) {
+                active.push(a.discord_id.clone());
+            }
+        }
+        active
+    }
+
+    /// Top members of a guild by rewards earned in it
+    pub fn guild_leaderboard(&self, guild_id: &str, limit: usize) -> Vec<(String, u64)> {
+        let mut members: Vec<(String, u64)> = self
+            .users
+            .values()
+            .filter_map(|user| {
+                user.guilds
+                    .get(guild_id)
+                    .map(|stats| (user.discord_id.clone(), stats.total_rewards))
+            })
+            .collect();
+        members.sort_by(|a, b| b.1.cmp(&a.1));
+        members.truncate(limit);
+        members
+    }
+
+    /// Keep a record of a reward granted to a user
+    pub fn log_reward(
+        &mut self,
+        discord_id: &str,
+        amount: u64,
+        source: &str,
+        guild_id: Option<&str>,
+    ) {
+        let log = self.rewards.entry(discord_id.to_string()).or_default();
+        log.push_back(Reward {
+            timestamp: ic::time(),
+            amount,
+            source: source.to_string(),
+            guild_id: guild_id.map(str::to_string),
+        });
+        if log.len() > REWARD_LOG_SIZE {
+            log.pop_front();
+        }
+    }
 }
 
 thread_local! {
@@ -65,8 +344,34 @@ thread_local! {
     None,
     HashMap::new(),
     HashMap::new(),
+    HashMap::new(),
+    HashMap::new(),
+    StreakFreezeConfig::default(),
+    VecDeque::new(),
+    None,
   ));
   static CUSTODIANS: RefCell<Vec<Principal>> = RefCell::new(Vec::new());
+  static DISCORD_ID: Regex = Regex::new(r"^\d{17,18}$").unwrap();
+}
+
+/// Allocate a token id to mint on the NFT canister: its total supply, or the next
+/// id after the ones handed out while their mints are still awaiting. Asking the
+/// canister each time picks up tokens minted by anyone else.
+pub async fn next_token_id(contract: &Principal) -> Result<Nat, String> {
+    let supply = _DIP721v2Proxy::_total_supply(contract).await?;
+    Ok(with_mut(|ledger| {
+        let id = match ledger.next_token_id.take() {
+            Some(next) if next > supply => next,
+            _ => supply,
+        };
+        ledger.next_token_id = Some(id.clone() + Nat::from(1));
+        id
+    }))
+}
+
+/// Check for a valid discord unique id
+pub fn is_valid_discord_id(discord_id: &str) -> bool {
+    DISCORD_ID.with(|re| re.is_match(discord_id))
 }
 
 // TODO: use controllers for ownership
@@ -82,6 +387,32 @@ pub fn _is_auth() -> Result<(), String> {
     })
 }
 
+pub fn _add_custodian(principal: Principal) -> Result<(), String> {
+    custodians_mut(|custodians| {
+        if principal == Principal::anonymous() {
+            return Err("Anonymous principal can't be a custodian".to_string());
+        }
+        if custodians.contains(&principal) {
+            return Err("Principal is already a custodian".to_string());
+        }
+        custodians.push(principal);
+        Ok(())
+    })
+}
+
+pub fn _remove_custodian(principal: Principal) -> Result<(), String> {
+    custodians_mut(|custodians| {
+        if !custodians.contains(&principal) {
+            return Err("Principal is not a custodian".to_string());
+        }
+        if custodians.len() == 1 {
+            return Err("Can't remove the last custodian".to_string());
+        }
+        custodians.retain(|c| c != &principal);
+        Ok(())
+    })
+}
+
 pub fn custodians_mut<T, F: FnOnce(&mut Vec<Principal>) -> T>(f: F) -> T {
     CUSTODIANS.with(|custodians| f(&mut custodians.borrow_mut()))
 }
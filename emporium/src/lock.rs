@@ -0,0 +1,29 @@
+use ic_kit::ic;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+/// A lock older than this is treated as released. Heartbeat tasks hold their lock
+/// across awaits, and a trap in a callback rolls back the release but not the
+/// acquire, which would otherwise stop the task for good.
+const LOCK_TIMEOUT: u64 = 15 * 60_000_000_000;
+
+pub type Lock = LocalKey<RefCell<Option<u64>>>;
+
+/// Take the lock, returning false if it is held and hasn't expired yet
+pub fn acquire(lock: &'static Lock) -> bool {
+    let now = ic::time();
+    lock.with(|held| {
+        let mut held = held.borrow_mut();
+        match *held {
+            Some(since) if now.saturating_sub(since) < LOCK_TIMEOUT => false,
+            _ => {
+                *held = Some(now);
+                true
+            }
+        }
+    })
+}
+
+pub fn release(lock: &'static Lock) {
+    lock.with(|held| held.replace(None));
+}
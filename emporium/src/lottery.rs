@@ -0,0 +1,508 @@
+use crate::accounts;
+use crate::audit;
+use crate::dip20::{_history_inc, _spend_allowance, _transfer, balance_of, queue_cap_record};
+use crate::events::{self, EventKind};
+use crate::ledger::{self, _is_auth};
+use crate::lock;
+use crate::maintenance::{self, Subsystem};
+use crate::metrics;
+use crate::moderation;
+use crate::random;
+use crate::rate_limit;
+use crate::tip::ensure_spendable;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+const ONE_DAY: u64 = 86_400_000_000_000;
+const MAX_BPS: u64 = 10_000;
+/// Drawn rounds kept for queries, older ones are only in cap
+const MAX_PAST_ROUNDS: usize = 100;
+const MAX_TICKETS_PER_BUY: u64 = 100;
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct LotteryConfig {
+    pub ticket_price: Nat,
+    /// time from the start of a round to its draw, in nanoseconds
+    pub round_length: u64,
+    /// share of the pot paid per winning ticket, first prize first, in basis points.
+    /// What isn't paid out rolls over to the next round.
+    pub tiers: Vec<u64>,
+}
+
+impl Default for LotteryConfig {
+    fn default() -> Self {
+        Self {
+            ticket_price: Nat::from(10),
+            round_length: 7 * ONE_DAY,
+            tiers: vec![5_000, 2_500, 1_500],
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Tickets {
+    pub discord_id: String,
+    pub principal: Principal,
+    pub count: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Winner {
+    pub tier: u64,
+    pub discord_id: String,
+    pub ticket: u64,
+    pub prize: Nat,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Round {
+    pub id: u64,
+    pub starts_at: u64,
+    pub draws_at: u64,
+    /// tickets in order of purchase, ticket numbers count up across the entries
+    pub tickets: Vec<Tickets>,
+    pub total_tickets: u64,
+    /// pot at the draw, including what rolled over
+    pub pot: Nat,
+    pub winners: Vec<Winner>,
+    /// `raw_rand` bytes winning tickets were derived from, with `random::derive`
+    pub randomness: Option<Vec<u8>>,
+}
+
+impl Round {
+    fn new(id: u64, now: u64, round_length: u64) -> Self {
+        Self {
+            id,
+            starts_at: now,
+            draws_at: now + round_length,
+            tickets: vec![],
+            total_tickets: 0,
+            pot: Nat::from(0),
+            winners: vec![],
+            randomness: None,
+        }
+    }
+
+    /// Owner of a ticket number
+    fn holder(&self, ticket: u64) -> Option<&Tickets> {
+        let mut end = 0;
+        self.tickets.iter().find(|t| {
+            end += t.count;
+            ticket < end
+        })
+    }
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Lottery {
+    pub config: LotteryConfig,
+    pub current: Option<Round>,
+    pub next_id: u64,
+    pub past: VecDeque<Round>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct RoundInfo {
+    pub id: u64,
+    pub starts_at: u64,
+    pub draws_at: u64,
+    pub total_tickets: u64,
+    pub players: u64,
+    pub pot: Nat,
+    pub winners: Vec<Winner>,
+    pub randomness: Option<Vec<u8>>,
+}
+
+impl From<&Round> for RoundInfo {
+    fn from(round: &Round) -> Self {
+        let mut players: Vec<&String> = round.tickets.iter().map(|t| &t.discord_id).collect();
+        players.sort();
+        players.dedup();
+        Self {
+            id: round.id,
+            starts_at: round.starts_at,
+            draws_at: round.draws_at,
+            total_tickets: round.total_tickets,
+            players: players.len() as u64,
+            pot: round.pot.clone(),
+            winners: round.winners.clone(),
+            randomness: round.randomness.clone(),
+        }
+    }
+}
+
+thread_local! {
+  static LOTTERY: RefCell<Lottery> = RefCell::new(Lottery::default());
+  static DRAWING: RefCell<Option<u64>> = RefCell::new(None);
+}
+
+pub fn with<T, F: FnOnce(&Lottery) -> T>(f: F) -> T {
+    LOTTERY.with(|lottery| f(&lottery.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Lottery) -> T>(f: F) -> T {
+    LOTTERY.with(|lottery| f(&mut lottery.borrow_mut()))
+}
+
+fn lottery_event(caller: Principal, op: &str, details: Vec<(String, DetailValue)>) {
+    let mut details = details;
+    details.push(("timestamp".to_string(), DetailValue::U64(ic::time())));
+    queue_cap_record(IndefiniteEvent {
+        caller,
+        operation: op.to_string(),
+        details,
+    });
+}
+
+/// Buy tickets for the running round, the price goes to the pot. Like a tip, the bot
+/// needs an allowance from the buyer covering the price.
+#[update]
+#[candid_method]
+fn buy_tickets(discord_id: String, count: u64) -> Result<String, String> {
+    let res = _buy_tickets(discord_id, count);
+    metrics::observe("buy_tickets", &res);
+    res
+}
+
+fn _buy_tickets(discord_id: String, count: u64) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Games)?;
+    let caller = ic::caller();
+    rate_limit::check("buy_tickets", caller, Some(&discord_id))?;
+    moderation::ensure_allowed(&discord_id)?;
+
+    if count == 0 || count > MAX_TICKETS_PER_BUY {
+        return Err(format!(
+            "Buy between 1 and {} tickets at a time",
+            MAX_TICKETS_PER_BUY
+        ));
+    }
+    let now = ic::time();
+    let (round_id, draws_at, price) = with(|lottery| {
+        lottery
+            .current
+            .as_ref()
+            .filter(|round| round.draws_at > now)
+            .map(|round| {
+                (
+                    round.id,
+                    round.draws_at,
+                    lottery.config.ticket_price.clone(),
+                )
+            })
+    })
+    .ok_or("The lottery is being drawn, try again shortly")?;
+    let player = ledger::with(|ledger| ledger.users.get(&discord_id).map(|u| u.principal))
+        .ok_or_else(|| format!("<@{}>, you are not registered", discord_id))?;
+
+    let total = price * Nat::from(count);
+    let delegated = ensure_spendable(caller, &discord_id, player, &total)?;
+    let pot = accounts::lottery_pot();
+    _transfer(player, pot, total.clone());
+    if delegated {
+        _spend_allowance(player, caller, total.clone());
+    }
+    _history_inc();
+
+    let held = with_mut(|lottery| {
+        let round = lottery.current.as_mut().unwrap();
+        round.total_tickets += count;
+        match round.tickets.last_mut() {
+            // merge consecutive buys so ticket numbers stay contiguous per entry
+            Some(last) if last.discord_id == discord_id => last.count += count,
+            _ => round.tickets.push(Tickets {
+                discord_id: discord_id.clone(),
+                principal: player,
+                count,
+            }),
+        }
+        round
+            .tickets
+            .iter()
+            .filter(|t| t.discord_id == discord_id)
+            .map(|t| t.count)
+            .sum::<u64>()
+    });
+
+    lottery_event(
+        caller,
+        "lottery_ticket",
+        vec![
+            ("round".to_string(), DetailValue::U64(round_id)),
+            ("from".to_string(), DetailValue::from(player)),
+            ("to".to_string(), DetailValue::from(pot)),
+            (
+                "discord_id".to_string(),
+                DetailValue::Text(discord_id.clone()),
+            ),
+            ("tickets".to_string(), DetailValue::U64(count)),
+            ("amount".to_string(), DetailValue::from(total.clone())),
+        ],
+    );
+
+    Ok(format!(
+        "<@{}> bought {} lottery tickets for `{} EMP`, holding {} in round #{}. \
+        The pot is `{} EMP`, drawn <t:{}:R>",
+        discord_id,
+        count,
+        total,
+        held,
+        round_id,
+        balance_of(pot),
+        draws_at / 1_000_000_000
+    ))
+}
+
+/// Draw a tier's ticket among the ones that haven't won yet: the `index`th ticket
+/// left, counting from 0, with `index` derived from the randomness and the tier.
+/// `None` once every ticket has won.
+fn winning_ticket(randomness: &[u8], tier: u64, total_tickets: u64, won: &[u64]) -> Option<u64> {
+    let left = total_tickets
+        .checked_sub(won.len() as u64)
+        .filter(|left| *left > 0)?;
+    let mut ticket = random::derive(randomness, tier) % left;
+    let mut won = won.to_vec();
+    won.sort_unstable();
+    for winner in won {
+        if winner <= ticket {
+            ticket += 1;
+        }
+    }
+    Some(ticket)
+}
+
+/// Pay out a drawn round from the pot, each tier's ticket derived from the randomness.
+/// A ticket wins at most one tier, tiers left once every ticket won roll over.
+fn settle(round: &mut Round, tiers: &[u64], randomness: Vec<u8>) {
+    let pot = accounts::lottery_pot();
+    round.pot = balance_of(pot);
+
+    let mut won = vec![];
+    for (tier, bps) in tiers.iter().enumerate() {
+        let ticket = match winning_ticket(&randomness, tier as u64, round.total_tickets, &won) {
+            Some(ticket) => ticket,
+            None => break,
+        };
+        won.push(ticket);
+
+        let holder = round.holder(ticket).unwrap().clone();
+        let prize = round.pot.clone() * Nat::from(*bps) / Nat::from(MAX_BPS);
+        if prize > 0 {
+            _transfer(pot, holder.principal, prize.clone());
+            _history_inc();
+        }
+        lottery_event(
+            ic::id(),
+            "lottery_win",
+            vec![
+                ("round".to_string(), DetailValue::U64(round.id)),
+                ("from".to_string(), DetailValue::from(pot)),
+                ("to".to_string(), DetailValue::from(holder.principal)),
+                (
+                    "discord_id".to_string(),
+                    DetailValue::Text(holder.discord_id.clone()),
+                ),
+                ("tier".to_string(), DetailValue::U64(tier as u64)),
+                ("ticket".to_string(), DetailValue::U64(ticket)),
+                ("amount".to_string(), DetailValue::from(prize.clone())),
+            ],
+        );
+        round.winners.push(Winner {
+            tier: tier as u64,
+            discord_id: holder.discord_id,
+            ticket,
+            prize,
+        });
+    }
+
+    lottery_event(
+        ic::id(),
+        "lottery_draw",
+        vec![
+            ("round".to_string(), DetailValue::U64(round.id)),
+            ("tickets".to_string(), DetailValue::U64(round.total_tickets)),
+            ("pot".to_string(), DetailValue::from(round.pot.clone())),
+            (
+                "randomness".to_string(),
+                DetailValue::Text(random::to_hex(&randomness)),
+            ),
+        ],
+    );
+    round.randomness = Some(randomness);
+}
+
+fn start_round(lottery: &mut Lottery, now: u64) {
+    lottery.current = Some(Round::new(
+        lottery.next_id,
+        now,
+        lottery.config.round_length,
+    ));
+    lottery.next_id += 1;
+}
+
+fn announce(round: &Round) -> String {
+    if round.total_tickets == 0 {
+        return format!(
+            ":tickets: Lottery round #{} had no tickets, `{} EMP` rolls over to the next round",
+            round.id, round.pot
+        );
+    }
+    let winners: String = round
+        .winners
+        .iter()
+        .map(|w| format!("\n{}. <@{}> `{} EMP`", w.tier + 1, w.discord_id, w.prize))
+        .collect();
+    format!(
+        ":tickets: Lottery round #{} drawn with {} tickets!{}",
+        round.id, round.total_tickets, winners
+    )
+}
+
+/// Draw the current round once it's due and start the next one. Rounds without
+/// tickets are drawn with no winners, and the pot rolls over. Called from the
+/// canister heartbeat.
+pub async fn draw() {
+    let now = ic::time();
+    let due = with(|lottery| lottery.current.as_ref().map(|round| round.draws_at <= now));
+    match due {
+        Some(true) => {}
+        Some(false) => return,
+        None => return with_mut(|lottery| start_round(lottery, now)),
+    }
+    if !lock::acquire(&DRAWING) {
+        return;
+    }
+
+    // tickets can't be bought once the round is due, so the draw sees every ticket
+    match random::bytes().await {
+        Ok(randomness) => with_mut(|lottery| {
+            let tiers = lottery.config.tiers.clone();
+            // an expired lock may let a second draw through, only one settles the round
+            let mut round = match lottery.current.take() {
+                Some(round) => round,
+                None => return,
+            };
+            settle(&mut round, &tiers, randomness);
+
+            events::publish(EventKind::LotteryDraw, announce(&round));
+
+            lottery.past.push_back(round);
+            if lottery.past.len() > MAX_PAST_ROUNDS {
+                lottery.past.pop_front();
+            }
+            start_round(lottery, ic::time());
+        }),
+        // retried next heartbeat
+        Err(e) => ic::print(format!("lottery draw failed: {}", e)),
+    }
+
+    lock::release(&DRAWING);
+}
+
+#[query]
+#[candid_method(query)]
+fn current_lottery() -> Option<RoundInfo> {
+    with(|lottery| {
+        lottery.current.as_ref().map(|round| RoundInfo {
+            pot: balance_of(accounts::lottery_pot()),
+            ..round.into()
+        })
+    })
+}
+
+/// Get drawn rounds, newest first
+#[query]
+#[candid_method(query)]
+fn get_lottery_rounds(limit: u64) -> Vec<RoundInfo> {
+    with(|lottery| {
+        lottery
+            .past
+            .iter()
+            .rev()
+            .take(limit as usize)
+            .map(RoundInfo::from)
+            .collect()
+    })
+}
+
+/// Get the tickets of a round with their holders, to check the draw
+#[query]
+#[candid_method(query)]
+fn get_lottery_tickets(round_id: u64) -> Option<Vec<Tickets>> {
+    with(|lottery| {
+        lottery
+            .current
+            .iter()
+            .chain(lottery.past.iter())
+            .find(|round| round.id == round_id)
+            .map(|round| round.tickets.clone())
+    })
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Set the lottery config, a new round length applies from the next round
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_lottery_config(config: LotteryConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res = if config.tiers.iter().sum::<u64>() > MAX_BPS {
+        Err(format!("Tiers can't add up to more than {}", MAX_BPS))
+    } else if config.round_length == 0 {
+        Err("Round length must be more than 0".to_string())
+    } else {
+        with_mut(|lottery| lottery.config = config);
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_lottery_config", args, &res);
+    res
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_lottery_config() -> LotteryConfig {
+    with(|lottery| lottery.config.clone())
+}
+
+// END CUSTODIAN METHODS //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn winning_tickets_skip_tickets_that_won() {
+        let randomness = vec![7; 32];
+        let mut won = vec![];
+        for tier in 0..5 {
+            let ticket = winning_ticket(&randomness, tier, 5, &won).unwrap();
+            assert!(ticket < 5);
+            assert!(!won.contains(&ticket));
+            won.push(ticket);
+        }
+        assert_eq!(winning_ticket(&randomness, 5, 5, &won), None);
+    }
+
+    #[test]
+    fn a_single_ticket_wins_one_tier() {
+        let randomness = vec![1; 32];
+        assert_eq!(winning_ticket(&randomness, 0, 1, &[]), Some(0));
+        assert_eq!(winning_ticket(&randomness, 1, 1, &[0]), None);
+        assert_eq!(winning_ticket(&randomness, 0, 0, &[]), None);
+    }
+
+    #[test]
+    fn winning_ticket_counts_the_tickets_left() {
+        // with ticket 1 out of 3 won, the tickets left are 0 and 2
+        let randomness = vec![3; 32];
+        let index = random::derive(&randomness, 1) % 2;
+        let expected = if index == 0 { 0 } else { 2 };
+        assert_eq!(winning_ticket(&randomness, 1, 3, &[1]), Some(expected));
+    }
+}
@@ -2,24 +2,52 @@ use crate::dip20::*;
 use cap_sdk::{archive, from_archive, Archive};
 use chrono::{Duration, TimeZone, Utc};
 use compile_time_run::run_command_str;
+use events::EventKind;
 use ic_cdk::export::Principal;
+use ic_cdk_macros::{heartbeat, inspect_message};
 use ic_kit::{
     candid::{candid_method, CandidType, Deserialize, Nat},
     ic,
     macros::*,
 };
 use ledger::_is_auth;
-use regex::Regex;
+use maintenance::Subsystem;
+use multisig::Operation;
 use std::convert::TryInto;
+use treasury::Category;
 
-// mod http;
+mod accounts;
+mod achievements;
+mod auctions;
+mod audit;
 mod dip20;
+mod emission;
+mod events;
+mod games;
+mod gateway;
+mod governance;
+mod http;
 mod ledger;
+mod lock;
+mod lottery;
+mod maintenance;
+mod marketplace;
+mod metrics;
+mod moderation;
+mod multisig;
+mod perks;
+mod quests;
+mod random;
+mod rate_limit;
+mod staking;
+mod tip;
 mod token_proxy;
+mod treasury;
 
 const ONE_HOUR: u64 = 3_600_000_000_000;
 const ONE_MINUTE: u64 = 60_000_000_000;
-const BASE_REWARD: u64 = 100;
+/// Max argument size accepted from non custodians
+const MAX_ARG_SIZE: usize = 2048;
 
 // Discord emojis
 const FIRE_EMOJI: &str = "<a:fire_anim:992513469041623080>";
@@ -36,15 +64,6 @@ const RICH_RAIN_PEPE: &str = "<a:rich_rain_pepe:992836844125438022>";
 //     NARUTO_PEPE,
 // ];
 
-// #[update]
-// #[candid_method]
-// async fn test_get() -> String {
-//   format!(
-//     "{:?}",
-//     http::get("https://rickandmortyapi.com/api/character/1".to_string()).await
-//   )
-// }
-
 // BEGIN QUERY METHODS //
 
 #[derive(Clone, Deserialize, CandidType)]
@@ -54,20 +73,39 @@ struct BalanceResponse {
     total_rewards: Nat,
     daily_streak: Nat,
     work_streak: Nat,
+    streak_freezes: Nat,
 }
 
-/// Get the balance of a user. Contains no sensitive information.
+/// Get the balance of a user, their rewards across guilds and their best streaks in
+/// any guild. Contains no sensitive information.
 #[query]
 #[candid_method(query)]
 fn user_balance(discord_id: String) -> Result<BalanceResponse, String> {
     let user: ledger::User =
         ledger::with(|ledger| ledger.users.get(&discord_id).cloned()).ok_or("User not found :(")?;
+    let best = |streak: fn(&ledger::GuildStats) -> u64| {
+        user.guilds.values().map(streak).max().unwrap_or(0)
+    };
     Ok(BalanceResponse {
         discord_id: format!("<@{}>", user.discord_id.clone()),
         balance: balance_of(user.principal),
         total_rewards: Nat::from(user.total_rewards),
-        daily_streak: Nat::from(user.daily.streak),
-        work_streak: Nat::from(user.work.streak),
+        daily_streak: Nat::from(best(|stats| stats.daily.streak)),
+        work_streak: Nat::from(best(|stats| stats.work.streak)),
+        streak_freezes: Nat::from(user.streak_freezes),
+    })
+}
+
+/// Get a user's streaks and rewards in a guild
+#[query]
+#[candid_method(query)]
+fn user_guild_stats(guild_id: String, discord_id: String) -> Result<ledger::GuildStats, String> {
+    ledger::with(|ledger| {
+        let user = ledger.users.get(&discord_id).ok_or("User not found :(")?;
+        user.guilds
+            .get(&guild_id)
+            .cloned()
+            .ok_or_else(|| "User is not a member of this guild".to_string())
     })
 }
 
@@ -115,45 +153,119 @@ fn get_users() -> Vec<ledger::User> {
 
 #[update(guard = "_is_auth")]
 #[candid_method]
-fn reset_daily_work_time(discord_id: String) -> Result<String, String> {
-    ledger::with_mut(|data| {
-        let mut user = data.users.get_mut(&discord_id).ok_or("asdf")?;
+fn reset_daily_work_time(guild_id: String, discord_id: String) -> Result<String, String> {
+    let res = ledger::with_mut(|data| {
+        let user = data.users.get_mut(&discord_id).ok_or("User not found")?;
+        let stats = user
+            .guilds
+            .get_mut(&guild_id)
+            .ok_or("User is not a member of this guild")?;
 
-        user.daily.last_timestamp -= 24 * ONE_HOUR;
-        user.work.last_timestamp -= ONE_HOUR;
+        stats.daily.last_timestamp -= 24 * ONE_HOUR;
+        stats.work.last_timestamp -= ONE_HOUR;
         let r = format!("reset {} work and daily time stamps", discord_id);
 
         Ok(r)
+    });
+    audit::record(
+        ic::caller(),
+        "reset_daily_work_time",
+        format!("guild_id: {}, discord_id: {}", guild_id, discord_id),
+        &res,
+    );
+    res
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+struct GuildInfo {
+    guild_id: String,
+    name: String,
+    config: ledger::GuildConfig,
+    members: u64,
+}
+
+/// Get all registered guilds
+#[query]
+#[candid_method(query)]
+fn get_guilds() -> Vec<GuildInfo> {
+    ledger::with(|ledger| {
+        ledger
+            .guilds
+            .iter()
+            .map(|(guild_id, guild)| GuildInfo {
+                guild_id: guild_id.clone(),
+                name: guild.name.clone(),
+                config: guild.config.clone(),
+                members: guild.members,
+            })
+            .collect()
     })
 }
 
+#[derive(Clone, Deserialize, CandidType)]
+struct LeaderboardEntry {
+    discord_id: String,
+    total_rewards: u64,
+}
+
+/// Get a guild's top members by rewards earned in the guild
+#[query]
+#[candid_method(query)]
+fn guild_leaderboard(guild_id: String, limit: usize) -> Vec<LeaderboardEntry> {
+    ledger::with(|ledger| ledger.guild_leaderboard(&guild_id, limit.min(100)))
+        .into_iter()
+        .map(|(discord_id, total_rewards)| LeaderboardEntry {
+            discord_id,
+            total_rewards,
+        })
+        .collect()
+}
+
 // END QUERY METHODS //
 
 // BEGIN USER METHODS //
 
-/// Register daily submission for user, requires registration
+fn perk_announcement(perk_bonus: u64) -> String {
+    if perk_bonus > 0 {
+        format!("\n:gem: `+{} EMP` from your items", perk_bonus)
+    } else {
+        String::new()
+    }
+}
+
+/// Register daily submission for user in a guild, requires registration
 ///
 /// Users can only submit once per day, minumum 20 hours after previous submission,
 /// with the streak running out after 28 hrs.
 #[update]
 #[candid_method]
-async fn daily(discord_user: String) -> Result<String, String> {
+async fn daily(guild_id: String, discord_user: String) -> Result<String, String> {
+    let res = _daily(guild_id, discord_user).await;
+    metrics::observe("daily", &res);
+    res
+}
+
+async fn _daily(guild_id: String, discord_user: String) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Rewards)?;
+    rate_limit::check("daily", ic::caller(), Some(&discord_user))?;
+    moderation::ensure_allowed(&discord_user)?;
+
+    let principal = ledger::with(|ledger| ledger.users.get(&discord_user).map(|u| u.principal))
+        .ok_or("Unregistered user")?;
+    let perks = perks::get(principal).await;
+
     let res = ledger::with_mut(|data| {
-        let mut user = data
-            .users
-            .get_mut(&discord_user)
-            .ok_or("Unregistered user")?;
+        let (config, user, mut stats) = data.member_mut(&guild_id, &discord_user)?;
+        let principal = user.principal;
 
         let time = ic::time();
 
         let now = Utc.timestamp_nanos(time.try_into().unwrap());
         let last = Utc
-            .timestamp_nanos(user.daily.last_timestamp.try_into().unwrap())
+            .timestamp_nanos(stats.daily.last_timestamp.try_into().unwrap())
             .date()
             .and_hms(0, 0, 0);
 
-        ic::print(format!("{}\n{}\n", last, now));
-
         let duration = now - last;
 
         // if were within the last day (UTC), reject the user
@@ -170,30 +282,60 @@ async fn daily(discord_user: String) -> Result<String, String> {
             ));
         }
 
-        // reset streak if last is more than a day old (this is super lenient for the streak)
+        // reset streak if last is more than a day old (this is super lenient for the streak),
+        // unless the user holds a streak freeze for every missed day
+        let mut streak = stats.daily.streak;
+        let mut freezes_used = 0;
         if duration.num_days() > 1 {
-            user.daily.streak = 0;
+            let missed = (duration.num_days() - 1) as u64;
+            if user.streak_freezes >= missed {
+                freezes_used = missed;
+            } else {
+                streak = 0;
+            }
         }
 
-        // // user gets exponentially increasing amounts the longer the streak
-        // // TODO: Define a more gradual increase, and taper off at a price
-        let bonus = user.daily.streak.pow(2);
-        user.total_rewards += BASE_REWARD + bonus;
-        user.daily.streak += 1;
-        user.daily.last_timestamp = time;
+        // user gets exponentially increasing amounts the longer the streak, up to the guild's cap
+        let base = emission::scale(config.daily_reward);
+        let bonus = streak.pow(2).min(config.max_streak_bonus);
+        let reward = perks.daily(base + bonus);
+        let perk_bonus = reward.saturating_sub(base + bonus);
+        // before any state changes, so a claim over budget can be retried
+        emission::spend(reward).map_err(|e| format!("<@{}>, {}", discord_user, e))?;
 
-        Ok((user.principal, bonus, user.daily.streak))
+        user.streak_freezes -= freezes_used;
+        stats.total_rewards += reward;
+        stats.daily.streak = streak + 1;
+        stats.daily.last_timestamp = time;
+        let streak = stats.daily.streak;
+        user.total_rewards += reward;
+
+        data.save_member(&guild_id, &discord_user, stats);
+        data.touch(&guild_id, &discord_user, time);
+        data.log_reward(&discord_user, reward, "daily", Some(guild_id.as_str()));
+        Ok((principal, base, bonus, perk_bonus, streak, freezes_used))
     });
 
     match res {
-        Ok((principal, bonus, streak)) => {
-            dip20::mint(principal, Nat::from(BASE_REWARD + bonus))
+        Ok((principal, base, bonus, perk_bonus, streak, freezes_used)) => {
+            dip20::_mint(principal, Nat::from(base + bonus + perk_bonus))
                 .await
                 .map_err(|e| format!("{:?}", e))?;
+            let completed = quests::record(&discord_user, quests::Objective::Daily);
+            let unlocked = achievements::check(&discord_user);
+            if events::is_streak_milestone(streak) {
+                events::publish(
+                    EventKind::StreakMilestone,
+                    format!(
+                        "<@{}> hit a {} day daily streak! {}",
+                        discord_user, streak, FIRE_EMOJI
+                    ),
+                );
+            }
             Ok(format!(
-                "<@{}>, claimed `{} EMP` daily rewards{}",
+                "<@{}>, claimed `{} EMP` daily rewards{}{}{}{}{}",
                 discord_user,
-                BASE_REWARD,
+                base,
                 if bonus > 0 {
                     format!(
                         ", plus `{} EMP` streak bonus for {} days {}",
@@ -202,26 +344,46 @@ async fn daily(discord_user: String) -> Result<String, String> {
                 } else {
                     format!(" {}", FIRE_EMOJI)
                 },
+                perk_announcement(perk_bonus),
+                match freezes_used {
+                    0 => String::new(),
+                    1 => "\n:ice_cube: A streak freeze saved your streak!".to_string(),
+                    n => format!("\n:ice_cube: {} streak freezes saved your streak!", n),
+                },
+                quests::announce(&completed),
+                achievements::announce(&unlocked),
             ))
         }
         Err(e) => Err(e),
     }
 }
 
-/// Register work submission for user, requires registration
+/// Register work submission for user in a guild, requires registration
 ///
 /// Users can only submit once every hour, with the streak running out after 2 hours
 #[update]
 #[candid_method]
-async fn work(discord_user: String) -> Result<String, String> {
+async fn work(guild_id: String, discord_user: String) -> Result<String, String> {
+    let res = _work(guild_id, discord_user).await;
+    metrics::observe("work", &res);
+    res
+}
+
+async fn _work(guild_id: String, discord_user: String) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Rewards)?;
+    rate_limit::check("work", ic::caller(), Some(&discord_user))?;
+    moderation::ensure_allowed(&discord_user)?;
+
+    let principal = ledger::with(|ledger| ledger.users.get(&discord_user).map(|u| u.principal))
+        .ok_or("Unregistered user")?;
+    let perks = perks::get(principal).await;
+
     let res = ledger::with_mut(|data| {
-        let mut user = data
-            .users
-            .get_mut(&discord_user)
-            .ok_or("Unregistered user")?;
+        let (config, user, mut stats) = data.member_mut(&guild_id, &discord_user)?;
+        let principal = user.principal;
 
         let now = ic::time();
-        let difference = now - user.work.last_timestamp;
+        let difference = now - stats.work.last_timestamp;
 
         // check if user has submitted in the last ONE_HOUR
         if difference < ONE_HOUR {
@@ -234,28 +396,41 @@ async fn work(discord_user: String) -> Result<String, String> {
 
         // update the user's work streak
         // reset streak if last was over 2 hrs
-        if now - user.work.last_timestamp > 2 * ONE_HOUR {
-            user.work.streak = 0;
-        }
-        // user gets exponentially increasing amounts the longer the streak
-        // TODO: Define a more gradual increase, and taper off at a price
-        let bonus = user.work.streak.pow(2);
-        user.total_rewards += BASE_REWARD + bonus;
-        user.work.streak += 1;
-        user.work.last_timestamp = now;
-
-        Ok((user.principal, bonus))
+        let streak = if now - stats.work.last_timestamp > 2 * ONE_HOUR {
+            0
+        } else {
+            stats.work.streak
+        };
+        // user gets exponentially increasing amounts the longer the streak, up to the guild's cap
+        let base = emission::scale(config.work_reward);
+        let bonus = streak.pow(2).min(config.max_streak_bonus);
+        let reward = perks.work(base + bonus);
+        let perk_bonus = reward.saturating_sub(base + bonus);
+        // before any state changes, so a claim over budget can be retried
+        emission::spend(reward).map_err(|e| format!("<@{}>, {}", discord_user, e))?;
+
+        stats.total_rewards += reward;
+        stats.work.streak = streak + 1;
+        stats.work.last_timestamp = now;
+        user.total_rewards += reward;
+
+        data.save_member(&guild_id, &discord_user, stats);
+        data.touch(&guild_id, &discord_user, now);
+        data.log_reward(&discord_user, reward, "work", Some(guild_id.as_str()));
+        Ok((principal, base, bonus, perk_bonus))
     });
 
     match res {
-        Ok((principal, bonus)) => {
-            dip20::mint(principal, Nat::from(BASE_REWARD + bonus))
+        Ok((principal, base, bonus, perk_bonus)) => {
+            dip20::_mint(principal, Nat::from(base + bonus + perk_bonus))
                 .await
                 .map_err(|e| format!("{:?}", e))?;
+            let completed = quests::record(&discord_user, quests::Objective::Work);
+            let unlocked = achievements::check(&discord_user);
             Ok(format!(
-                "<@{}>, claimed `{} EMP` work rewards{}",
+                "<@{}>, claimed `{} EMP` work rewards{}{}{}{}",
                 discord_user,
-                BASE_REWARD,
+                base,
                 if bonus > 0 {
                     format!(
                         ", plus `{} EMP` for being on the grind! {} ",
@@ -264,6 +439,9 @@ async fn work(discord_user: String) -> Result<String, String> {
                 } else {
                     format!(" {}", FIRE_EMOJI)
                 },
+                perk_announcement(perk_bonus),
+                quests::announce(&completed),
+                achievements::announce(&unlocked),
             ))
         }
         Err(e) => Err(e),
@@ -274,11 +452,18 @@ async fn work(discord_user: String) -> Result<String, String> {
 #[update]
 #[candid_method]
 fn register(discord_user: String, auth: Option<ledger::AuthToken>) -> Result<String, String> {
-    // regex check for valid discord username
-    let re = Regex::new(r"^\d{17,18}$").unwrap();
-    if !re.is_match(&discord_user) {
+    let res = _register(discord_user, auth);
+    metrics::observe("register", &res);
+    res
+}
+
+fn _register(discord_user: String, auth: Option<ledger::AuthToken>) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Registration)?;
+
+    if !ledger::is_valid_discord_id(&discord_user) {
         return Err("Invalid discord unique id".to_string());
     }
+    rate_limit::check("register", ic::caller(), Some(&discord_user))?;
 
     ledger::with_mut(|data| {
         let caller = ic::caller();
@@ -296,6 +481,14 @@ fn register(discord_user: String, auth: Option<ledger::AuthToken>) -> Result<Str
         data.principals.insert(caller, discord_user.clone());
         data.total_users += 1;
 
+        events::publish(
+            EventKind::Registration,
+            format!(
+                "Welcome <@{}>, the emporium's {} registered user!",
+                discord_user, data.total_users
+            ),
+        );
+
         Ok(format!(
             "<@{}>, registered principal id: `{:}`",
             discord_user, caller
@@ -307,6 +500,14 @@ fn register(discord_user: String, auth: Option<ledger::AuthToken>) -> Result<Str
 #[update]
 #[candid_method]
 fn set_principal(discord_user: String, principal: Principal) -> Result<(), String> {
+    let res = _set_principal(discord_user, principal);
+    metrics::observe("set_principal", &res);
+    res
+}
+
+fn _set_principal(discord_user: String, principal: Principal) -> Result<(), String> {
+    rate_limit::check("set_principal", ic::caller(), Some(&discord_user))?;
+
     ledger::with_mut(|data| {
         let mut user = data
             .users
@@ -324,8 +525,167 @@ fn set_principal(discord_user: String, principal: Principal) -> Result<(), Strin
     })
 }
 
+/// Buy streak freezes, paid for from the user's balance into the treasury.
+/// Can be called by the user's principal, or a custodian on their behalf.
+#[update]
+#[candid_method]
+async fn buy_streak_freeze(discord_user: String, count: u64) -> Result<String, String> {
+    let res = _buy_streak_freeze(discord_user, count).await;
+    metrics::observe("buy_streak_freeze", &res);
+    res
+}
+
+async fn _buy_streak_freeze(discord_user: String, count: u64) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Shop)?;
+    moderation::ensure_allowed(&discord_user)?;
+    let caller = ic::caller();
+
+    let (principal, price, held) = ledger::with_mut(|ledger| {
+        let config = ledger.streak_freeze.clone();
+        let user = ledger
+            .users
+            .get_mut(&discord_user)
+            .ok_or("Unregistered user")?;
+
+        if caller != user.principal && _is_auth().is_err() {
+            return Err("You are not authorized to buy for this user".to_string());
+        }
+        if count == 0 {
+            return Err("Count must be at least 1".to_string());
+        }
+        let held = user
+            .streak_freezes
+            .checked_add(count)
+            .filter(|held| *held <= config.max_held)
+            .ok_or_else(|| {
+                format!(
+                    "<@{}>, you can hold at most {} streak freezes",
+                    discord_user, config.max_held
+                )
+            })?;
+        let price = config
+            .price
+            .checked_mul(count)
+            .ok_or("Price overflows, buy fewer streak freezes")?;
+        if balance_of(user.principal) < Nat::from(price) {
+            return Err(format!("<@{}>, insufficient balance", discord_user));
+        }
+
+        treasury::deposit(user.principal, Nat::from(price), Category::Shop);
+        user.streak_freezes = held;
+        Ok((user.principal, price, held))
+    })?;
+
+    metrics::shop_sale("streak_freeze", Nat::from(price));
+    _history_inc();
+    add_record(
+        caller,
+        "buy",
+        principal,
+        accounts::treasury(),
+        Nat::from(price),
+        Nat::from(0),
+        ic::time(),
+    )
+    .await
+    .map_err(|e| format!("{:?}", e))?;
+
+    let completed = quests::record(&discord_user, quests::Objective::Purchase);
+    let unlocked = achievements::record_purchase(&discord_user);
+    Ok(format!(
+        "<@{}>, bought {} streak freeze{} for `{} EMP`, you now hold {} :ice_cube:{}{}",
+        discord_user,
+        count,
+        if count == 1 { "" } else { "s" },
+        price,
+        held,
+        quests::announce(&completed),
+        achievements::announce(&unlocked),
+    ))
+}
+
 // END USER METHODS //
 
+// BEGIN CUSTODIAN METHODS //
+
+#[query]
+#[candid_method(query)]
+fn custodians() -> Vec<Principal> {
+    ledger::custodians_mut(|custodians| custodians.clone())
+}
+
+/// Add a custodian, with full admin access to the canister
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn add_custodian(principal: Principal) -> Result<(), String> {
+    let res = multisig::ensure_direct(&Operation::AddCustodian(principal))
+        .and_then(|_| ledger::_add_custodian(principal));
+    audit::record(
+        ic::caller(),
+        "add_custodian",
+        format!("principal: {}", principal),
+        &res,
+    );
+    res
+}
+
+/// Remove a custodian, the last custodian can't be removed
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn remove_custodian(principal: Principal) -> Result<(), String> {
+    let res = multisig::ensure_direct(&Operation::RemoveCustodian(principal))
+        .and_then(|_| ledger::_remove_custodian(principal));
+    audit::record(
+        ic::caller(),
+        "remove_custodian",
+        format!("principal: {}", principal),
+        &res,
+    );
+    res
+}
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_streak_freeze_config(config: ledger::StreakFreezeConfig) {
+    audit::record_ok("set_streak_freeze_config", format!("{:?}", config));
+    ledger::with_mut(|ledger| ledger.streak_freeze = config);
+}
+
+#[query]
+#[candid_method(query)]
+fn get_streak_freeze_config() -> ledger::StreakFreezeConfig {
+    ledger::with(|ledger| ledger.streak_freeze.clone())
+}
+
+/// Register a guild or update its name and reward config
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_guild(guild_id: String, name: String, config: ledger::GuildConfig) -> Result<(), String> {
+    let args = format!("guild_id: {}, name: {}, {:?}", guild_id, name, config);
+    let res = if guild_id != ledger::DEFAULT_GUILD && !ledger::is_valid_discord_id(&guild_id) {
+        Err("Invalid guild id".to_string())
+    } else {
+        ledger::with_mut(|ledger| {
+            let guild = ledger
+                .guilds
+                .entry(guild_id)
+                .or_insert_with(|| ledger::Guild {
+                    name: String::new(),
+                    config: ledger::GuildConfig::default(),
+                    created_at: ic::time(),
+                    members: 0,
+                });
+            guild.name = name;
+            guild.config = config;
+        });
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_guild", args, &res);
+    res
+}
+
+// END CUSTODIAN METHODS //
+
 // BEGIN CANISTER SETUP //
 
 #[derive(Clone, Deserialize, Debug, CandidType)]
@@ -339,8 +699,10 @@ pub struct InitArgs {
 #[candid_method(init)]
 fn init(args: Option<InitArgs>) {
     let args = args.unwrap();
+    _set_fee_to(accounts::treasury());
     ledger::with_mut(|ledger| {
         ledger.nft_canister = args.nft_canister;
+        ledger.next_token_id = None;
         cap_sdk::handshake(1_000_000_000_000, args.cap_canister);
     });
 
@@ -354,6 +716,32 @@ fn init(args: Option<InitArgs>) {
     })
 }
 
+/// State of the feature modules, kept in one record as candid tuples
+/// (and so the stable tuple) are limited to 16 elements. Every field is optional
+/// so modules can be added without breaking the decoding of older stable memory,
+/// missing ones start from their default.
+#[derive(CandidType, Default, Deserialize)]
+struct ModuleState {
+    events: Option<events::Events>,
+    metrics: Option<metrics::Metrics>,
+    maintenance: Option<maintenance::Maintenance>,
+    audit: Option<audit::AuditLog>,
+    multisig: Option<multisig::Multisig>,
+    rate_limits: Option<rate_limit::RateLimits>,
+    moderation: Option<moderation::Moderation>,
+    achievements: Option<achievements::Achievements>,
+    quests: Option<quests::Quests>,
+    perks: Option<perks::PerkConfig>,
+    games: Option<games::Games>,
+    lottery: Option<lottery::Lottery>,
+    staking: Option<staking::Staking>,
+    emission: Option<emission::Emission>,
+    treasury: Option<treasury::Treasury>,
+    governance: Option<governance::Governance>,
+    marketplace: Option<marketplace::Marketplace>,
+    auctions: Option<auctions::Auctions>,
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
     let ledger_clone = ledger::with(|ledger| ledger.clone());
@@ -363,6 +751,26 @@ fn pre_upgrade() {
     let allows = ALLOWS.with(|a| a.borrow().clone());
     let tx_log = TXLOG.with(|t| t.borrow().clone());
     let cap = archive();
+    let modules = ModuleState {
+        events: Some(events::with(|events| events.clone())),
+        metrics: Some(metrics::with(|metrics| metrics.clone())),
+        maintenance: Some(maintenance::with(|maintenance| maintenance.clone())),
+        audit: Some(audit::with(|audit| audit.clone())),
+        multisig: Some(multisig::with(|multisig| multisig.clone())),
+        rate_limits: Some(rate_limit::with(|limits| limits.clone())),
+        moderation: Some(moderation::with(|moderation| moderation.clone())),
+        achievements: Some(achievements::with(|achievements| achievements.clone())),
+        quests: Some(quests::with(|quests| quests.clone())),
+        perks: Some(perks::with(|config| config.clone())),
+        games: Some(games::with(|games| games.clone())),
+        lottery: Some(lottery::with(|lottery| lottery.clone())),
+        staking: Some(staking::with(|staking| staking.clone())),
+        emission: Some(emission::with(|emission| emission.clone())),
+        treasury: Some(treasury::with(|treasury| treasury.clone())),
+        governance: Some(governance::with(|governance| governance.clone())),
+        marketplace: Some(marketplace::with(|marketplace| marketplace.clone())),
+        auctions: Some(auctions::with(|auctions| auctions.clone())),
+    };
     ic::stable_store((
         ledger_clone,
         custodians,
@@ -371,6 +779,7 @@ fn pre_upgrade() {
         allows,
         tx_log,
         cap,
+        Some(modules),
     ))
     .unwrap();
 }
@@ -385,7 +794,8 @@ fn post_upgrade() {
         allowances_stored,
         tx_log_stored,
         cap,
-    ): (
+        modules,
+    ) = match ic::stable_restore::<(
         ledger::Ledger,
         Vec<Principal>,
         StatsData,
@@ -393,9 +803,31 @@ fn post_upgrade() {
         Allowances,
         TxLog,
         Archive,
-    ) = ic::stable_restore().unwrap();
+        Option<ModuleState>,
+    )>() {
+        Ok(stored) => stored,
+        // stable memory saved before the feature modules, as a 7-tuple
+        Err(_) => {
+            let (ledger, custodians, stats, balances, allowances, tx_log, cap): (
+                ledger::LegacyLedger,
+                Vec<Principal>,
+                StatsData,
+                Balances,
+                Allowances,
+                TxLog,
+                Archive,
+            ) = ic::stable_restore().unwrap();
+            let ledger = ledger.migrate(ic::time());
+            (
+                ledger, custodians, stats, balances, allowances, tx_log, cap, None,
+            )
+        }
+    };
+    let modules = modules.unwrap_or_default();
     ledger::with_mut(|ledger| {
         *ledger = ledger_stored;
+        // ids are re-read from the NFT canister, in case it changed
+        ledger.next_token_id = None;
     });
     ledger::custodians_mut(|custodians| {
         *custodians = custodians_stored.clone();
@@ -417,6 +849,154 @@ fn post_upgrade() {
         *tx_log = tx_log_stored;
     });
     from_archive(cap);
+    // fees used to go to the anonymous principal until fee_to was set
+    if STATS.with(|s| s.borrow().fee_to == Principal::anonymous()) {
+        _set_fee_to(accounts::treasury());
+    }
+    events::with_mut(|events| {
+        *events = modules.events.unwrap_or_default();
+    });
+    metrics::with_mut(|metrics| {
+        *metrics = modules.metrics.unwrap_or_default();
+    });
+    maintenance::with_mut(|maintenance| {
+        *maintenance = modules.maintenance.unwrap_or_default();
+    });
+    audit::with_mut(|audit| {
+        *audit = modules.audit.unwrap_or_default();
+    });
+    multisig::with_mut(|multisig| {
+        *multisig = modules.multisig.unwrap_or_default();
+    });
+    rate_limit::with_mut(|limits| {
+        *limits = modules.rate_limits.unwrap_or_default();
+    });
+    moderation::with_mut(|moderation| {
+        *moderation = modules.moderation.unwrap_or_default();
+    });
+    achievements::with_mut(|achievements| {
+        *achievements = modules.achievements.unwrap_or_default();
+    });
+    quests::with_mut(|quests| {
+        *quests = modules.quests.unwrap_or_default();
+    });
+    perks::with_mut(|config| {
+        *config = modules.perks.unwrap_or_default();
+    });
+    games::with_mut(|games| {
+        *games = modules.games.unwrap_or_default();
+    });
+    lottery::with_mut(|lottery| {
+        *lottery = modules.lottery.unwrap_or_default();
+    });
+    staking::with_mut(|staking| {
+        *staking = modules.staking.unwrap_or_default();
+    });
+    emission::with_mut(|emission| {
+        *emission = modules.emission.unwrap_or_default();
+    });
+    treasury::with_mut(|treasury| {
+        *treasury = modules.treasury.unwrap_or_default();
+    });
+    governance::with_mut(|governance| {
+        *governance = modules.governance.unwrap_or_default();
+    });
+    marketplace::with_mut(|marketplace| {
+        *marketplace = modules.marketplace.unwrap_or_default();
+    });
+    auctions::with_mut(|auctions| {
+        *auctions = modules.auctions.unwrap_or_default();
+    });
+}
+
+/// Reject obvious garbage before it is executed: anonymous callers, oversized
+/// arguments, unknown guilds, invalid discord ids and callers out of rate limit tokens
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let caller = ic::caller();
+
+    if caller == Principal::anonymous() {
+        return;
+    }
+    if _is_auth().is_ok() {
+        ic_cdk::api::call::accept_message();
+        return;
+    }
+    if ic_cdk::api::call::arg_data_raw().len() > MAX_ARG_SIZE {
+        return;
+    }
+    if ["daily", "work"].contains(&method.as_str()) {
+        // traps (rejecting the message) if the arguments aren't text
+        let (guild_id, discord_id): (String, String) = ic_cdk::api::call::arg_data();
+        if !ledger::with(|ledger| ledger.guilds.contains_key(&guild_id))
+            || !ledger::is_valid_discord_id(&discord_id)
+        {
+            return;
+        }
+    }
+    if method == "rain" {
+        let (_, from, _, _): (String, String, Nat, u64) = ic_cdk::api::call::arg_data();
+        if !ledger::is_valid_discord_id(&from) {
+            return;
+        }
+    }
+    if method == "tip" {
+        let (from, to, _, _): (String, String, Nat, String) = ic_cdk::api::call::arg_data();
+        if !ledger::is_valid_discord_id(&from) || !ledger::is_valid_discord_id(&to) {
+            return;
+        }
+    }
+    if method == "coinflip" {
+        let (discord_id, _, _): (String, Nat, games::Side) = ic_cdk::api::call::arg_data();
+        if !ledger::is_valid_discord_id(&discord_id) {
+            return;
+        }
+    }
+    if method == "buy_tickets" {
+        let (discord_id, _): (String, u64) = ic_cdk::api::call::arg_data();
+        if !ledger::is_valid_discord_id(&discord_id) {
+            return;
+        }
+    }
+    if method == "dice" {
+        let (discord_id, _, _): (String, Nat, u64) = ic_cdk::api::call::arg_data();
+        if !ledger::is_valid_discord_id(&discord_id) {
+            return;
+        }
+    }
+    if method == "bid" {
+        let (discord_id, _, _): (String, u64, Nat) = ic_cdk::api::call::arg_data();
+        if !ledger::is_valid_discord_id(&discord_id) {
+            return;
+        }
+    }
+    if ["register", "set_principal"].contains(&method.as_str()) {
+        // traps (rejecting the message) if the first argument isn't text
+        let (discord_id,): (String,) = ic_cdk::api::call::arg_data();
+        if !ledger::is_valid_discord_id(&discord_id) {
+            return;
+        }
+    }
+    if !rate_limit::peek(&method, caller) {
+        return;
+    }
+
+    ic_cdk::api::call::accept_message();
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    quests::rotate();
+    governance::finalize();
+    gateway::certify();
+    events::process_outbox().await;
+    audit::archive().await;
+    dip20::flush_cap().await;
+    achievements::mint_badges().await;
+    lottery::draw().await;
+    marketplace::prune().await;
+    auctions::settle().await;
 }
 
 #[query(name = "gitCommitHash")]
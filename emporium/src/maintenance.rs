@@ -0,0 +1,109 @@
+use crate::audit;
+use crate::ledger::_is_auth;
+use crate::multisig::{self, Operation};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    Rewards,
+    Transfers,
+    Approvals,
+    Shop,
+    Registration,
+    Games,
+    Staking,
+    Marketplace,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Pause {
+    pub reason: String,
+    pub paused_by: Principal,
+    pub paused_at: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Maintenance {
+    pub paused: HashMap<Subsystem, Pause>,
+}
+
+thread_local! {
+  static MAINTENANCE: RefCell<Maintenance> = RefCell::new(Maintenance::default());
+}
+
+pub fn with<T, F: FnOnce(&Maintenance) -> T>(f: F) -> T {
+    MAINTENANCE.with(|maintenance| f(&maintenance.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Maintenance) -> T>(f: F) -> T {
+    MAINTENANCE.with(|maintenance| f(&mut maintenance.borrow_mut()))
+}
+
+/// Err with a user facing message if `subsystem` is paused
+pub fn ensure_active(subsystem: Subsystem) -> Result<(), String> {
+    with(|maintenance| match maintenance.paused.get(&subsystem) {
+        Some(pause) => Err(format!(
+            "{:?} paused for maintenance: {}",
+            subsystem, pause.reason
+        )),
+        None => Ok(()),
+    })
+}
+
+pub fn _set_paused(subsystem: Subsystem, paused: bool, reason: String) {
+    with_mut(|maintenance| {
+        if paused {
+            maintenance.paused.insert(
+                subsystem,
+                Pause {
+                    reason,
+                    paused_by: ic::caller(),
+                    paused_at: ic::time(),
+                },
+            );
+        } else {
+            maintenance.paused.remove(&subsystem);
+        }
+    })
+}
+
+/// Pause or resume a subsystem.
+///
+/// With multisig enabled any custodian can pause, resuming requires a proposal.
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_paused(subsystem: Subsystem, paused: bool, reason: String) -> Result<(), String> {
+    let args = format!("{:?}: {}, reason: {}", subsystem, paused, reason);
+    let res = if paused {
+        Ok(())
+    } else {
+        multisig::ensure_direct(&Operation::SetPaused {
+            subsystem,
+            paused,
+            reason: reason.clone(),
+        })
+    }
+    .map(|_| _set_paused(subsystem, paused, reason));
+    audit::record(ic::caller(), "set_paused", args, &res);
+    res
+}
+
+/// Get the currently paused subsystems
+#[query]
+#[candid_method(query)]
+fn get_paused() -> Vec<(Subsystem, Pause)> {
+    with(|maintenance| {
+        maintenance
+            .paused
+            .iter()
+            .map(|(subsystem, pause)| (*subsystem, pause.clone()))
+            .collect()
+    })
+}
@@ -0,0 +1,422 @@
+use crate::accounts;
+use crate::audit;
+use crate::dip20::{_history_inc, _transfer, balance_of, queue_cap_record, transfer_event};
+use crate::ledger::{self, _is_auth};
+use crate::lock;
+use crate::maintenance::{self, Subsystem};
+use crate::metrics;
+use crate::moderation;
+use crate::rate_limit;
+use crate::token_proxy::_DIP721v2Proxy;
+use crate::treasury::{self, Category};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+const MAX_BPS: u64 = 10_000;
+/// Listings checked for staleness per run
+const LISTINGS_PER_TICK: usize = 5;
+/// How often the heartbeat checks listings for staleness
+const PRUNE_INTERVAL: u64 = 60_000_000_000;
+/// Sales kept for queries, older ones are only in cap
+const MAX_SALES: usize = 1_000;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct MarketConfig {
+    /// marketplace fee paid into the treasury, in basis points of the price
+    pub fee_bps: u64,
+    /// royalty paid to `royalty_to`, in basis points of the price
+    pub royalty_bps: u64,
+    /// creator receiving royalties, none are charged if not set
+    pub royalty_to: Option<Principal>,
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        Self {
+            fee_bps: 250,
+            royalty_bps: 500,
+            royalty_to: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Listing {
+    pub token_id: Nat,
+    pub seller: Principal,
+    pub price: Nat,
+    pub listed_at: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Sale {
+    pub token_id: Nat,
+    pub seller: Principal,
+    pub buyer: Principal,
+    pub price: Nat,
+    pub fee: Nat,
+    pub royalty: Nat,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Marketplace {
+    pub config: MarketConfig,
+    pub listings: BTreeMap<Nat, Listing>,
+    pub sales: VecDeque<Sale>,
+    /// next token id the heartbeat checks for staleness
+    pub prune_cursor: Nat,
+}
+
+thread_local! {
+  static MARKETPLACE: RefCell<Marketplace> = RefCell::new(Marketplace::default());
+  static PRUNING: RefCell<Option<u64>> = RefCell::new(None);
+  static LAST_PRUNE: RefCell<u64> = RefCell::new(0);
+}
+
+pub fn with<T, F: FnOnce(&Marketplace) -> T>(f: F) -> T {
+    MARKETPLACE.with(|marketplace| f(&marketplace.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Marketplace) -> T>(f: F) -> T {
+    MARKETPLACE.with(|marketplace| f(&mut marketplace.borrow_mut()))
+}
+
+fn nft_canister() -> Result<Principal, String> {
+    ledger::with(|ledger| ledger.nft_canister).ok_or_else(|| "No NFT canister set".to_string())
+}
+
+/// Check the seller still owns the token and the canister may still operate it.
+/// Errs if the NFT canister couldn't be reached.
+async fn is_valid(
+    contract: &Principal,
+    token_id: &Nat,
+    seller: &Principal,
+) -> Result<bool, String> {
+    match _DIP721v2Proxy::_owner_of(contract, token_id).await {
+        Ok(owner) if owner == Some(*seller) => {}
+        Ok(_) => return Ok(false),
+        Err(e) if e == "TokenNotFound" => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    match _DIP721v2Proxy::_operator_of(contract, token_id).await {
+        Ok(operator) => Ok(operator == Some(ic::id())),
+        Err(e) if e == "OperatorNotFound" => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// List a token for sale. The caller must own it, and have approved the canister
+/// to operate it so the sale can transfer it.
+#[update]
+#[candid_method]
+async fn list_item(token_id: Nat, price: Nat) -> Result<Listing, String> {
+    let res = _list_item(token_id, price).await;
+    metrics::observe("list_item", &res);
+    res
+}
+
+async fn _list_item(token_id: Nat, price: Nat) -> Result<Listing, String> {
+    maintenance::ensure_active(Subsystem::Marketplace)?;
+    let caller = ic::caller();
+    rate_limit::check("list_item", caller, None)?;
+    moderation::ensure_allowed_principal(caller)?;
+
+    if price == 0 {
+        return Err("Price must be more than 0".to_string());
+    }
+    let contract = nft_canister()?;
+    if !is_valid(&contract, &token_id, &caller).await? {
+        return Err("You must own the token and approve the canister as its operator".into());
+    }
+
+    let listing = Listing {
+        token_id: token_id.clone(),
+        seller: caller,
+        price,
+        listed_at: ic::time(),
+    };
+    with_mut(|m| m.listings.insert(token_id, listing.clone()));
+    Ok(listing)
+}
+
+/// Buy a listed token. The price is escrowed while the token is transferred, then
+/// split between the seller, the royalty and the marketplace fee. If the transfer
+/// fails the listing is removed as stale and the price refunded.
+#[update]
+#[candid_method]
+async fn buy_listing(token_id: Nat) -> Result<Sale, String> {
+    let res = _buy_listing(token_id).await;
+    metrics::observe("buy_listing", &res);
+    res
+}
+
+async fn _buy_listing(token_id: Nat) -> Result<Sale, String> {
+    maintenance::ensure_active(Subsystem::Marketplace)?;
+    let caller = ic::caller();
+    rate_limit::check("buy_listing", caller, None)?;
+    moderation::ensure_allowed_principal(caller)?;
+
+    let contract = nft_canister()?;
+    let listing = with(|m| m.listings.get(&token_id).cloned()).ok_or("Listing not found")?;
+    if listing.seller == caller {
+        return Err("You can't buy your own listing".to_string());
+    }
+    if balance_of(caller) < listing.price {
+        return Err("Insufficient balance".to_string());
+    }
+
+    // take the listing and escrow the price before awaiting, so it can't be bought twice
+    let escrow = accounts::escrow();
+    with_mut(|m| m.listings.remove(&token_id));
+    _transfer(caller, escrow, listing.price.clone());
+    queue_cap_record(transfer_event(
+        caller,
+        "market_escrow",
+        caller,
+        escrow,
+        listing.price.clone(),
+        Nat::from(0),
+        ic::time(),
+    ));
+    _history_inc();
+
+    if let Err(e) =
+        _DIP721v2Proxy::_transfer_from(&listing.seller, &caller, &token_id, &contract).await
+    {
+        _transfer(escrow, caller, listing.price.clone());
+        queue_cap_record(transfer_event(
+            caller,
+            "market_refund",
+            escrow,
+            caller,
+            listing.price.clone(),
+            Nat::from(0),
+            ic::time(),
+        ));
+        _history_inc();
+        return Err(format!("Listing is no longer valid and was removed: {}", e));
+    }
+
+    let config = with(|m| m.config.clone());
+    let share = |bps: u64| listing.price.clone() * Nat::from(bps) / Nat::from(MAX_BPS);
+    let fee = share(config.fee_bps);
+    let royalty = match config.royalty_to {
+        Some(_) => share(config.royalty_bps),
+        None => Nat::from(0),
+    };
+    let proceeds = listing.price.clone() - fee.clone() - royalty.clone();
+
+    _transfer(escrow, listing.seller, proceeds);
+    if let Some(royalty_to) = config.royalty_to {
+        _transfer(escrow, royalty_to, royalty.clone());
+    }
+    treasury::deposit(escrow, fee.clone(), Category::Marketplace);
+    _history_inc();
+
+    let sale = Sale {
+        token_id: token_id.clone(),
+        seller: listing.seller,
+        buyer: caller,
+        price: listing.price.clone(),
+        fee: fee.clone(),
+        royalty: royalty.clone(),
+        timestamp: ic::time(),
+    };
+    with_mut(|m| {
+        m.sales.push_back(sale.clone());
+        if m.sales.len() > MAX_SALES {
+            m.sales.pop_front();
+        }
+    });
+    queue_cap_record(IndefiniteEvent {
+        caller,
+        operation: "sale".to_string(),
+        details: vec![
+            ("token_id".to_string(), DetailValue::from(token_id)),
+            ("from".to_string(), DetailValue::from(caller)),
+            ("to".to_string(), DetailValue::from(listing.seller)),
+            ("amount".to_string(), DetailValue::from(listing.price)),
+            ("fee".to_string(), DetailValue::from(fee)),
+            ("royalty".to_string(), DetailValue::from(royalty)),
+            ("timestamp".to_string(), DetailValue::U64(sale.timestamp)),
+        ],
+    });
+    Ok(sale)
+}
+
+/// Remove a listing, by its seller or a custodian. Custodians removing someone
+/// else's listing are audited.
+#[update]
+#[candid_method]
+fn cancel_listing(token_id: Nat) -> Result<(), String> {
+    let caller = ic::caller();
+    let mut by_custodian = false;
+    let res = with_mut(|m| match m.listings.get(&token_id) {
+        Some(listing) if listing.seller == caller => {
+            m.listings.remove(&token_id);
+            Ok(())
+        }
+        Some(_) if _is_auth().is_ok() => {
+            by_custodian = true;
+            m.listings.remove(&token_id);
+            Ok(())
+        }
+        Some(_) => Err("You are not the seller of this listing".to_string()),
+        None => Err("Listing not found".to_string()),
+    });
+    if by_custodian {
+        audit::record(caller, "cancel_listing", token_id.to_string(), &res);
+    }
+    metrics::observe("cancel_listing", &res);
+    res
+}
+
+/// Remove listings whose seller no longer owns the token, or no longer lets the
+/// canister operate it, checking a few per run. Called from the canister heartbeat,
+/// at most once per `PRUNE_INTERVAL`.
+pub async fn prune() {
+    let now = ic::time();
+    if LAST_PRUNE.with(|l| now - *l.borrow() < PRUNE_INTERVAL) {
+        return;
+    }
+    let contract = match nft_canister() {
+        Ok(contract) => contract,
+        Err(_) => return,
+    };
+    if !lock::acquire(&PRUNING) {
+        return;
+    }
+    LAST_PRUNE.with(|l| l.replace(now));
+
+    let batch: Vec<Listing> = with(|m| {
+        m.listings
+            .range(m.prune_cursor.clone()..)
+            .take(LISTINGS_PER_TICK)
+            .map(|(_, listing)| listing.clone())
+            .collect()
+    });
+    // start over from the first listing once every listing was checked
+    let next_cursor = match batch.last() {
+        Some(listing) if batch.len() == LISTINGS_PER_TICK => {
+            listing.token_id.clone() + Nat::from(1)
+        }
+        _ => Nat::from(0),
+    };
+
+    for listing in batch {
+        match is_valid(&contract, &listing.token_id, &listing.seller).await {
+            Ok(true) => {}
+            Ok(false) => with_mut(|m| {
+                // the listing may have been replaced while awaiting
+                if m.listings
+                    .get(&listing.token_id)
+                    .map_or(false, |l| l.seller == listing.seller)
+                {
+                    m.listings.remove(&listing.token_id);
+                }
+            }),
+            Err(e) => ic::print(format!("listing check failed: {}", e)),
+        }
+    }
+
+    with_mut(|m| m.prune_cursor = next_cursor);
+    lock::release(&PRUNING);
+}
+
+/// Get listings ordered by token id, starting after `start`
+#[query]
+#[candid_method(query)]
+fn get_listings(start: Option<Nat>, limit: u64) -> Vec<Listing> {
+    with(|m| {
+        m.listings
+            .iter()
+            .filter(|(token_id, _)| start.as_ref().map_or(true, |start| *token_id > start))
+            .take((limit as usize).min(MAX_PAGE_SIZE))
+            .map(|(_, listing)| listing.clone())
+            .collect()
+    })
+}
+
+/// Get the latest sales, newest first
+#[query]
+#[candid_method(query)]
+fn get_sales(limit: u64) -> Vec<Sale> {
+    with(|m| {
+        m.sales
+            .iter()
+            .rev()
+            .take((limit as usize).min(MAX_PAGE_SIZE))
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_market_config() -> MarketConfig {
+    with(|m| m.config.clone())
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_market_config(config: MarketConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res = if config.fee_bps + config.royalty_bps > MAX_BPS {
+        Err(format!(
+            "Fee and royalty can't add up to more than {} basis points",
+            MAX_BPS
+        ))
+    } else {
+        with_mut(|m| m.config = config);
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_market_config", args, &res);
+    res
+}
+
+// END CUSTODIAN METHODS //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(token_id: u64) {
+        with_mut(|m| {
+            m.listings.insert(
+                Nat::from(token_id),
+                Listing {
+                    token_id: Nat::from(token_id),
+                    seller: Principal::anonymous(),
+                    price: Nat::from(100),
+                    listed_at: 0,
+                },
+            )
+        });
+    }
+
+    fn ids(listings: Vec<Listing>) -> Vec<Nat> {
+        listings.into_iter().map(|l| l.token_id).collect()
+    }
+
+    #[test]
+    fn get_listings_pages_by_token_id() {
+        for token_id in [3, 1, 2, 5] {
+            list(token_id);
+        }
+        let nats = |ids: &[u64]| ids.iter().map(|id| Nat::from(*id)).collect::<Vec<_>>();
+        assert_eq!(ids(get_listings(None, 2)), nats(&[1, 2]));
+        assert_eq!(ids(get_listings(Some(Nat::from(2)), 2)), nats(&[3, 5]));
+        assert_eq!(ids(get_listings(Some(Nat::from(5)), 2)), nats(&[]));
+    }
+}
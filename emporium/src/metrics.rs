@@ -0,0 +1,190 @@
+use crate::dip20::{BALANCES, STATS, TXLOG};
+use crate::ledger;
+use ic_kit::{
+    candid::{CandidType, Deserialize, Nat},
+    ic,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: u64 = 65536;
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct MethodStats {
+    pub calls: u64,
+    pub errors: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct ShopSales {
+    pub count: u64,
+    pub revenue: Nat,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Metrics {
+    pub methods: BTreeMap<String, MethodStats>,
+    pub shop_sales: BTreeMap<String, ShopSales>,
+}
+
+thread_local! {
+  static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+pub fn with<T, F: FnOnce(&Metrics) -> T>(f: F) -> T {
+    METRICS.with(|metrics| f(&metrics.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Metrics) -> T>(f: F) -> T {
+    METRICS.with(|metrics| f(&mut metrics.borrow_mut()))
+}
+
+/// Count a call to an update method, and whether it failed.
+/// Query calls can't be counted, as their state changes are discarded.
+pub fn observe<T, E>(method: &str, res: &Result<T, E>) {
+    with_mut(|metrics| {
+        let stats = metrics.methods.entry(method.to_string()).or_default();
+        stats.calls += 1;
+        if res.is_err() {
+            stats.errors += 1;
+        }
+    })
+}
+
+pub fn shop_sale(item: &str, price: Nat) {
+    with_mut(|metrics| {
+        let sales = metrics.shop_sales.entry(item.to_string()).or_default();
+        sales.count += 1;
+        sales.revenue += price;
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_size() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_size() -> u64 {
+    0
+}
+
+#[cfg(target_arch = "wasm32")]
+fn stable_size() -> u64 {
+    ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn stable_size() -> u64 {
+    0
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Render canister metrics in the prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    gauge(
+        &mut out,
+        "emporium_cycles_balance",
+        "Cycles balance of the canister",
+        ic::balance(),
+    );
+    gauge(
+        &mut out,
+        "emporium_stable_memory_bytes",
+        "Size of the stable memory",
+        stable_size(),
+    );
+    gauge(
+        &mut out,
+        "emporium_heap_memory_bytes",
+        "Size of the heap memory",
+        heap_size(),
+    );
+    gauge(
+        &mut out,
+        "emporium_users",
+        "Number of registered users",
+        ledger::with(|ledger| ledger.total_users),
+    );
+    gauge(
+        &mut out,
+        "emporium_holders",
+        "Number of EMP holders",
+        BALANCES.with(|b| b.borrow().len()),
+    );
+    gauge(
+        &mut out,
+        "emporium_total_supply",
+        "Total EMP supply",
+        // candid's Nat display adds `_` separators, which prometheus can't parse
+        STATS.with(|s| s.borrow().total_supply.0.clone()),
+    );
+    gauge(
+        &mut out,
+        "emporium_cap_retry_queue",
+        "Cap events waiting to be retried",
+        TXLOG.with(|t| t.borrow().ie_records.len()),
+    );
+
+    with(|metrics| {
+        let _ = writeln!(
+            out,
+            "# HELP emporium_method_calls_total Update calls per method"
+        );
+        let _ = writeln!(out, "# TYPE emporium_method_calls_total counter");
+        for (method, stats) in metrics.methods.iter() {
+            let _ = writeln!(
+                out,
+                "emporium_method_calls_total{{method=\"{}\"}} {}",
+                method, stats.calls
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP emporium_method_errors_total Failed update calls per method"
+        );
+        let _ = writeln!(out, "# TYPE emporium_method_errors_total counter");
+        for (method, stats) in metrics.methods.iter() {
+            let _ = writeln!(
+                out,
+                "emporium_method_errors_total{{method=\"{}\"}} {}",
+                method, stats.errors
+            );
+        }
+
+        let _ = writeln!(out, "# HELP emporium_shop_sales_total Shop sales per item");
+        let _ = writeln!(out, "# TYPE emporium_shop_sales_total counter");
+        for (item, sales) in metrics.shop_sales.iter() {
+            let _ = writeln!(
+                out,
+                "emporium_shop_sales_total{{item=\"{}\"}} {}",
+                item, sales.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP emporium_shop_revenue_total Shop revenue in EMP per item"
+        );
+        let _ = writeln!(out, "# TYPE emporium_shop_revenue_total counter");
+        for (item, sales) in metrics.shop_sales.iter() {
+            let _ = writeln!(
+                out,
+                "emporium_shop_revenue_total{{item=\"{}\"}} {}",
+                item, sales.revenue.0
+            );
+        }
+    });
+
+    out
+}
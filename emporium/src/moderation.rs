@@ -0,0 +1,327 @@
+use crate::audit;
+use crate::dip20::{_burn, _history_inc, add_record};
+use crate::ledger::{self, _is_auth};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Actions kept in the history, oldest are dropped first
+const MAX_HISTORY: usize = 10_000;
+
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum Target {
+    Discord(String),
+    Principal(Principal),
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Suspension {
+    pub reason: String,
+    pub moderator: Principal,
+    pub created_at: u64,
+    /// `None` for a permanent ban
+    pub expires_at: Option<u64>,
+}
+
+impl Suspension {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum ActionKind {
+    Suspend { expires_at: Option<u64> },
+    Lift,
+    Clawback { from: u64, to: u64, amount: Nat },
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct ModerationAction {
+    pub target: Target,
+    pub kind: ActionKind,
+    pub reason: String,
+    pub moderator: Principal,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Moderation {
+    pub moderators: Vec<Principal>,
+    pub suspensions: HashMap<Target, Suspension>,
+    pub history: VecDeque<ModerationAction>,
+}
+
+thread_local! {
+  static MODERATION: RefCell<Moderation> = RefCell::new(Moderation::default());
+}
+
+pub fn with<T, F: FnOnce(&Moderation) -> T>(f: F) -> T {
+    MODERATION.with(|moderation| f(&moderation.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Moderation) -> T>(f: F) -> T {
+    MODERATION.with(|moderation| f(&mut moderation.borrow_mut()))
+}
+
+/// Custodians and moderators
+pub fn _is_moderator() -> Result<(), String> {
+    if _is_auth().is_ok() || with(|moderation| moderation.moderators.contains(&ic::caller())) {
+        Ok(())
+    } else {
+        Err("Error: Unauthorized principal ID".to_string())
+    }
+}
+
+/// Find an active suspension of the targets, dropping their expired ones
+fn active_suspension(targets: &[Target]) -> Option<Suspension> {
+    let now = ic::time();
+    with_mut(|moderation| {
+        for target in targets {
+            if moderation
+                .suspensions
+                .get(target)
+                .map_or(false, |s| !s.is_active(now))
+            {
+                moderation.suspensions.remove(target);
+            }
+        }
+        targets
+            .iter()
+            .find_map(|target| moderation.suspensions.get(target))
+            .cloned()
+    })
+}
+
+fn suspended_error(suspension: Suspension) -> String {
+    match suspension.expires_at {
+        Some(expires_at) => format!(
+            "Account suspended for {} more minutes: {}",
+            (expires_at - ic::time()) / 60_000_000_000 + 1,
+            suspension.reason
+        ),
+        None => format!("Account banned: {}", suspension.reason),
+    }
+}
+
+fn user_targets(discord_id: &str) -> Vec<Target> {
+    let mut targets = vec![Target::Discord(discord_id.to_string())];
+    if let Some(principal) =
+        ledger::with(|ledger| ledger.users.get(discord_id).map(|u| u.principal))
+    {
+        targets.push(Target::Principal(principal));
+    }
+    targets
+}
+
+/// Err if the discord id or its registered principal is suspended
+pub fn ensure_allowed(discord_id: &str) -> Result<(), String> {
+    match active_suspension(&user_targets(discord_id)) {
+        Some(suspension) => Err(suspended_error(suspension)),
+        None => Ok(()),
+    }
+}
+
+/// Err if the principal or its registered discord id is suspended
+pub fn ensure_allowed_principal(principal: Principal) -> Result<(), String> {
+    let mut targets = vec![Target::Principal(principal)];
+    if let Some(discord_id) = ledger::with(|ledger| ledger.principals.get(&principal).cloned()) {
+        targets.push(Target::Discord(discord_id));
+    }
+    match active_suspension(&targets) {
+        Some(suspension) => Err(suspended_error(suspension)),
+        None => Ok(()),
+    }
+}
+
+fn log_action(target: Target, kind: ActionKind, reason: String) {
+    with_mut(|moderation| {
+        moderation.history.push_back(ModerationAction {
+            target,
+            kind,
+            reason,
+            moderator: ic::caller(),
+            timestamp: ic::time(),
+        });
+        if moderation.history.len() > MAX_HISTORY {
+            moderation.history.pop_front();
+        }
+    })
+}
+
+// BEGIN MODERATOR METHODS //
+
+/// Suspend a discord id or principal from rewards, the shop and transfers.
+/// `duration` is in nanoseconds, `null` bans for good.
+#[update(guard = "_is_moderator")]
+#[candid_method]
+fn suspend(target: Target, duration: Option<u64>, reason: String) -> Result<(), String> {
+    let args = format!("{:?}, duration: {:?}, reason: {}", target, duration, reason);
+    let res = if reason.trim().is_empty() {
+        Err("A reason is required".to_string())
+    } else {
+        let now = ic::time();
+        let expires_at = duration.map(|duration| now.saturating_add(duration));
+        with_mut(|moderation| {
+            moderation
+                .suspensions
+                .retain(|_, suspension| suspension.is_active(now));
+            moderation.suspensions.insert(
+                target.clone(),
+                Suspension {
+                    reason: reason.clone(),
+                    moderator: ic::caller(),
+                    created_at: now,
+                    expires_at,
+                },
+            )
+        });
+        log_action(target, ActionKind::Suspend { expires_at }, reason);
+        Ok(())
+    };
+    audit::record(ic::caller(), "suspend", args, &res);
+    res
+}
+
+#[update(guard = "_is_moderator")]
+#[candid_method]
+fn lift_suspension(target: Target, reason: String) -> Result<(), String> {
+    let args = format!("{:?}, reason: {}", target, reason);
+    let res = match with_mut(|moderation| moderation.suspensions.remove(&target)) {
+        Some(_) => {
+            log_action(target, ActionKind::Lift, reason);
+            Ok(())
+        }
+        None => Err("Target is not suspended".to_string()),
+    };
+    audit::record(ic::caller(), "lift_suspension", args, &res);
+    res
+}
+
+/// Burn rewards a user earned between `from` and `to` (inclusive, nanosecond timestamps)
+#[update(guard = "_is_moderator")]
+#[candid_method]
+async fn clawback(discord_id: String, from: u64, to: u64, reason: String) -> Result<Nat, String> {
+    let caller = ic::caller();
+    let args = format!(
+        "discord_id: {}, from: {}, to: {}, reason: {}",
+        discord_id, from, to, reason
+    );
+
+    let res = ledger::with_mut(|ledger| {
+        let principal = ledger
+            .users
+            .get(&discord_id)
+            .ok_or("User not found")?
+            .principal;
+
+        // clawed back rewards are removed from the log, so they can't be clawed back twice
+        let log = ledger.rewards.entry(discord_id.clone()).or_default();
+        let mut amount = 0u64;
+        let mut per_guild: BTreeMap<String, u64> = BTreeMap::new();
+        log.retain(|reward| {
+            let in_range = reward.timestamp >= from && reward.timestamp <= to;
+            if in_range {
+                amount = amount.saturating_add(reward.amount);
+                if let Some(guild_id) = &reward.guild_id {
+                    let clawed = per_guild.entry(guild_id.clone()).or_default();
+                    *clawed = clawed.saturating_add(reward.amount);
+                }
+            }
+            !in_range
+        });
+        if amount == 0 {
+            return Err("No rewards in range".to_string());
+        }
+
+        // the user may have spent part of the rewards, only what was burned is
+        // taken off their stats
+        let burned = _burn(principal, Nat::from(amount));
+        let mut remaining: u64 = burned.0.to_string().parse().unwrap_or(amount);
+        let user = ledger.users.get_mut(&discord_id).unwrap();
+        user.total_rewards = user.total_rewards.saturating_sub(remaining);
+        for (guild_id, clawed) in per_guild {
+            if let Some(stats) = user.guilds.get_mut(&guild_id) {
+                let taken = clawed.min(remaining);
+                stats.total_rewards = stats.total_rewards.saturating_sub(taken);
+                remaining -= taken;
+            }
+        }
+
+        Ok((principal, burned))
+    });
+
+    let res = match res {
+        Ok((principal, burned)) => {
+            log_action(
+                Target::Discord(discord_id),
+                ActionKind::Clawback {
+                    from,
+                    to,
+                    amount: burned.clone(),
+                },
+                reason,
+            );
+            _history_inc();
+            add_record(
+                caller,
+                "clawback",
+                principal,
+                Principal::anonymous(),
+                burned,
+                Nat::from(0),
+                ic::time(),
+            )
+            .await
+            .map_err(|e| format!("{:?}", e))
+        }
+        Err(e) => Err(e),
+    };
+    audit::record(caller, "clawback", args, &res);
+    res
+}
+
+#[query(guard = "_is_moderator")]
+#[candid_method(query)]
+fn get_moderation_history(target: Option<Target>) -> Vec<ModerationAction> {
+    with(|moderation| {
+        moderation
+            .history
+            .iter()
+            .rev()
+            .filter(|action| target.as_ref().map_or(true, |t| &action.target == t))
+            .cloned()
+            .collect()
+    })
+}
+
+// END MODERATOR METHODS //
+
+// BEGIN CUSTODIAN METHODS //
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_moderators(moderators: Vec<Principal>) {
+    audit::record_ok("set_moderators", format!("{:?}", moderators));
+    with_mut(|moderation| moderation.moderators = moderators);
+}
+
+#[query]
+#[candid_method(query)]
+fn moderators() -> Vec<Principal> {
+    with(|moderation| moderation.moderators.clone())
+}
+
+// END CUSTODIAN METHODS //
+
+/// Get a user's suspension, if any
+#[query]
+#[candid_method(query)]
+fn moderation_status(discord_id: String) -> Option<Suspension> {
+    active_suspension(&user_targets(&discord_id))
+}
@@ -0,0 +1,362 @@
+use crate::audit;
+use crate::dip20::{_batch_mint, _mint, _set_fee, _set_fee_to};
+use crate::ledger::{self, _is_auth};
+use crate::maintenance::{self, Subsystem};
+use crate::treasury;
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const ONE_DAY: u64 = 86_400_000_000_000;
+const MAX_PAGE_SIZE: usize = 100;
+/// Proposals kept, the oldest closed ones are dropped past this. Pending ones are
+/// always kept.
+const MAX_PROPOSALS: usize = 1_000;
+
+/// High impact admin operations, which require approval from multiple custodians
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum Operation {
+    Mint {
+        to: Principal,
+        amount: Nat,
+    },
+    BatchMint(Vec<(Principal, Nat)>),
+    SetFee(Nat),
+    SetFeeTo(Principal),
+    AddCustodian(Principal),
+    RemoveCustodian(Principal),
+    SetPaused {
+        subsystem: Subsystem,
+        paused: bool,
+        reason: String,
+    },
+    SetConfig(MultisigConfig),
+    TreasurySpend {
+        to: Principal,
+        amount: Nat,
+        memo: String,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct MultisigConfig {
+    /// approvals needed to execute an operation, 1 disables multisig
+    pub threshold: u64,
+    /// mints (or batch mints totalling) and treasury spends above this amount
+    /// require a proposal
+    pub mint_threshold: Nat,
+    /// proposals expire after this many nanoseconds
+    pub ttl: u64,
+}
+
+impl Default for MultisigConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1,
+            mint_threshold: Nat::from(10_000),
+            ttl: 3 * ONE_DAY,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq)]
+pub enum ProposalStatus {
+    Pending,
+    Executed,
+    Failed(String),
+    Expired,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Proposal {
+    pub id: u64,
+    pub operation: Operation,
+    pub proposer: Principal,
+    pub approvals: Vec<Principal>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub status: ProposalStatus,
+    pub executed_at: Option<u64>,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Multisig {
+    pub config: MultisigConfig,
+    pub next_id: u64,
+    pub proposals: BTreeMap<u64, Proposal>,
+}
+
+thread_local! {
+  static MULTISIG: RefCell<Multisig> = RefCell::new(Multisig::default());
+}
+
+pub fn with<T, F: FnOnce(&Multisig) -> T>(f: F) -> T {
+    MULTISIG.with(|multisig| f(&multisig.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Multisig) -> T>(f: F) -> T {
+    MULTISIG.with(|multisig| f(&mut multisig.borrow_mut()))
+}
+
+/// Drop the oldest executed, failed and expired proposals past `MAX_PROPOSALS`
+fn prune(multisig: &mut Multisig, now: u64) {
+    let excess = multisig.proposals.len().saturating_sub(MAX_PROPOSALS);
+    let closed: Vec<u64> = multisig
+        .proposals
+        .values()
+        .filter(|p| p.status != ProposalStatus::Pending || p.expires_at <= now)
+        .take(excess)
+        .map(|p| p.id)
+        .collect();
+    for id in closed {
+        multisig.proposals.remove(&id);
+    }
+}
+
+fn requires_proposal(config: &MultisigConfig, operation: &Operation) -> bool {
+    if config.threshold <= 1 {
+        return false;
+    }
+    match operation {
+        Operation::Mint { amount, .. } => *amount > config.mint_threshold,
+        Operation::BatchMint(mints) => {
+            let total = mints
+                .iter()
+                .fold(Nat::from(0), |total, (_, amount)| total + amount.clone());
+            total > config.mint_threshold
+        }
+        Operation::TreasurySpend { amount, .. } => *amount > config.mint_threshold,
+        _ => true,
+    }
+}
+
+/// Err if `operation` must go through a proposal instead of a direct call
+pub fn ensure_direct(operation: &Operation) -> Result<(), String> {
+    with(|multisig| {
+        if requires_proposal(&multisig.config, operation) {
+            Err(format!(
+                "Operation requires approval from {} custodians, submit it with `propose`",
+                multisig.config.threshold
+            ))
+        } else {
+            Ok(())
+        }
+    })
+}
+
+fn _set_config(config: MultisigConfig) -> Result<(), String> {
+    let custodians = ledger::custodians_mut(|custodians| custodians.len() as u64);
+    if config.threshold == 0 || config.threshold > custodians {
+        return Err(format!(
+            "Threshold must be between 1 and the number of custodians ({})",
+            custodians
+        ));
+    }
+    with_mut(|multisig| multisig.config = config);
+    Ok(())
+}
+
+async fn execute(operation: Operation) -> Result<(), String> {
+    match operation {
+        Operation::Mint { to, amount } => _mint(to, amount)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e)),
+        Operation::BatchMint(mints) => _batch_mint(mints)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e)),
+        Operation::SetFee(fee) => {
+            _set_fee(fee);
+            Ok(())
+        }
+        Operation::SetFeeTo(fee_to) => {
+            _set_fee_to(fee_to);
+            Ok(())
+        }
+        Operation::AddCustodian(principal) => ledger::_add_custodian(principal),
+        Operation::RemoveCustodian(principal) => {
+            let custodians = ledger::custodians_mut(|custodians| custodians.len() as u64);
+            if custodians <= with(|multisig| multisig.config.threshold) {
+                return Err("Removing this custodian would make the threshold unreachable".into());
+            }
+            ledger::_remove_custodian(principal)
+        }
+        Operation::SetPaused {
+            subsystem,
+            paused,
+            reason,
+        } => {
+            maintenance::_set_paused(subsystem, paused, reason);
+            Ok(())
+        }
+        Operation::SetConfig(config) => _set_config(config),
+        Operation::TreasurySpend { to, amount, memo } => {
+            treasury::_spend(to, amount, memo).map(|_| ())
+        }
+    }
+}
+
+/// Approve a pending proposal, executing it once the threshold is reached
+async fn approve_and_execute(id: u64, caller: Principal) -> Result<ProposalStatus, String> {
+    let now = ic::time();
+    let custodians = ledger::custodians_mut(|custodians| custodians.clone());
+    let ready = with_mut(|multisig| {
+        let threshold = multisig.config.threshold;
+        let proposal = multisig
+            .proposals
+            .get_mut(&id)
+            .ok_or("Proposal not found")?;
+
+        if proposal.status == ProposalStatus::Pending && proposal.expires_at <= now {
+            proposal.status = ProposalStatus::Expired;
+        }
+        if proposal.status != ProposalStatus::Pending {
+            return Err(format!("Proposal is {:?}", proposal.status));
+        }
+        if proposal.approvals.contains(&caller) {
+            return Err("Proposal already approved by caller".to_string());
+        }
+        proposal.approvals.push(caller);
+
+        // approvals of removed custodians no longer count
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|approver| custodians.contains(approver))
+            .count();
+        if (approvals as u64) < threshold {
+            return Ok(None);
+        }
+        // mark as executed before awaiting, so concurrent approvals can't execute it twice
+        proposal.status = ProposalStatus::Executed;
+        proposal.executed_at = Some(now);
+        Ok(Some(proposal.operation.clone()))
+    })?;
+
+    let operation = match ready {
+        Some(operation) => operation,
+        None => return Ok(ProposalStatus::Pending),
+    };
+
+    let args = format!("id: {}, {:?}", id, operation);
+    let res = execute(operation).await;
+    audit::record(caller, "execute_proposal", args, &res);
+
+    let status = match res {
+        Ok(()) => ProposalStatus::Executed,
+        Err(e) => ProposalStatus::Failed(e),
+    };
+    with_mut(|multisig| {
+        if let Some(proposal) = multisig.proposals.get_mut(&id) {
+            proposal.status = status.clone();
+        }
+    });
+    Ok(status)
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Propose a high impact operation. The proposer's approval is counted.
+#[update(guard = "_is_auth")]
+#[candid_method]
+async fn propose(operation: Operation) -> Result<u64, String> {
+    let caller = ic::caller();
+    let now = ic::time();
+    let args = format!("{:?}", operation);
+
+    let id = with_mut(|multisig| {
+        let id = multisig.next_id;
+        multisig.next_id += 1;
+        multisig.proposals.insert(
+            id,
+            Proposal {
+                id,
+                operation,
+                proposer: caller,
+                approvals: vec![],
+                created_at: now,
+                expires_at: now + multisig.config.ttl,
+                status: ProposalStatus::Pending,
+                executed_at: None,
+            },
+        );
+        prune(multisig, now);
+        id
+    });
+    audit::record(caller, "propose", args, &Ok::<u64, ()>(id));
+
+    approve_and_execute(id, caller).await?;
+    Ok(id)
+}
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+async fn approve_proposal(id: u64) -> Result<ProposalStatus, String> {
+    let caller = ic::caller();
+    let res = approve_and_execute(id, caller).await;
+    audit::record(caller, "approve_proposal", format!("id: {}", id), &res);
+    res
+}
+
+/// Set the multisig config directly, only while multisig is disabled
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_multisig_config(config: MultisigConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res =
+        ensure_direct(&Operation::SetConfig(config.clone())).and_then(|_| _set_config(config));
+    audit::record(ic::caller(), "set_multisig_config", args, &res);
+    res
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_multisig_config() -> MultisigConfig {
+    with(|multisig| multisig.config.clone())
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_pending_proposals() -> Vec<Proposal> {
+    let now = ic::time();
+    with(|multisig| {
+        multisig
+            .proposals
+            .values()
+            .filter(|p| p.status == ProposalStatus::Pending && p.expires_at > now)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Get executed, failed and expired proposals, newest first
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_proposal_history(start: usize, limit: usize) -> Vec<Proposal> {
+    let now = ic::time();
+    with(|multisig| {
+        multisig
+            .proposals
+            .values()
+            .rev()
+            .filter(|p| p.status != ProposalStatus::Pending || p.expires_at <= now)
+            .skip(start)
+            .take(limit.min(MAX_PAGE_SIZE))
+            .cloned()
+            .map(|mut p| {
+                if p.status == ProposalStatus::Pending {
+                    p.status = ProposalStatus::Expired;
+                }
+                p
+            })
+            .collect()
+    })
+}
+
+// END CUSTODIAN METHODS //
@@ -0,0 +1,293 @@
+use crate::audit;
+use crate::ledger::{self, _is_auth};
+use crate::token_proxy::{_DIP721v2Proxy, GenericValue, TokenMetadata};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const ONE_MINUTE: u64 = 60_000_000_000;
+
+/// Expired cache entries are pruned once this many are cached
+const MAX_CACHED: usize = 10_000;
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PerkConfig {
+    /// cap on stacked multipliers, in percent (300 = 3x)
+    pub max_multiplier: u64,
+    /// cap on stacked flat bonuses, in EMP
+    pub max_bonus: u64,
+    /// how long a user's perks are cached, in nanoseconds
+    pub cache_ttl: u64,
+}
+
+impl Default for PerkConfig {
+    fn default() -> Self {
+        Self {
+            max_multiplier: 300,
+            max_bonus: 1_000,
+            cache_ttl: 10 * ONE_MINUTE,
+        }
+    }
+}
+
+/// Reward modifiers from a user's NFTs. Multipliers are in percent (150 = 1.5x).
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq)]
+pub struct Perks {
+    pub daily_multiplier: u64,
+    pub work_multiplier: u64,
+    pub daily_bonus: u64,
+    pub work_bonus: u64,
+}
+
+impl Default for Perks {
+    fn default() -> Self {
+        Self {
+            daily_multiplier: 100,
+            work_multiplier: 100,
+            daily_bonus: 0,
+            work_bonus: 0,
+        }
+    }
+}
+
+impl Perks {
+    /// Apply the daily perks to a reward
+    pub fn daily(&self, reward: u64) -> u64 {
+        (reward.saturating_mul(self.daily_multiplier) / 100).saturating_add(self.daily_bonus)
+    }
+
+    /// Apply the work perks to a reward
+    pub fn work(&self, reward: u64) -> u64 {
+        (reward.saturating_mul(self.work_multiplier) / 100).saturating_add(self.work_bonus)
+    }
+}
+
+struct Cached {
+    perks: Perks,
+    fetched_at: u64,
+}
+
+thread_local! {
+  static CONFIG: RefCell<PerkConfig> = RefCell::new(PerkConfig::default());
+  // the cache is not persisted, perks are fetched again after an upgrade
+  static CACHE: RefCell<HashMap<Principal, Cached>> = RefCell::new(HashMap::new());
+}
+
+pub fn with<T, F: FnOnce(&PerkConfig) -> T>(f: F) -> T {
+    CONFIG.with(|config| f(&config.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut PerkConfig) -> T>(f: F) -> T {
+    CONFIG.with(|config| f(&mut config.borrow_mut()))
+}
+
+/// Read a numeric property, floats are read as percentages (1.5 = 150)
+fn as_u64(value: &GenericValue) -> Option<u64> {
+    match value {
+        GenericValue::Nat8Content(n) => Some(*n as u64),
+        GenericValue::Nat16Content(n) => Some(*n as u64),
+        GenericValue::Nat32Content(n) => Some(*n as u64),
+        GenericValue::Nat64Content(n) => Some(*n),
+        GenericValue::NatContent(n) => n.to_string().replace('_', "").parse().ok(),
+        GenericValue::TextContent(text) => text.parse().ok(),
+        GenericValue::FloatContent(f) if *f >= 0.0 => Some((f * 100.0).round() as u64),
+        _ => None,
+    }
+}
+
+/// Stack the perks of every owned token, multipliers add up (two 1.5x items are 2x)
+fn stack(tokens: &[TokenMetadata]) -> Perks {
+    let config = with(|config| config.clone());
+    let mut perks = Perks::default();
+
+    for token in tokens.iter().filter(|token| !token.is_burned) {
+        for (key, value) in token.properties.iter() {
+            let value = match as_u64(value) {
+                Some(value) => value,
+                None => continue,
+            };
+            match key.as_str() {
+                "daily_multiplier" => {
+                    perks.daily_multiplier = perks
+                        .daily_multiplier
+                        .saturating_add(value.saturating_sub(100))
+                }
+                "work_multiplier" => {
+                    perks.work_multiplier = perks
+                        .work_multiplier
+                        .saturating_add(value.saturating_sub(100))
+                }
+                "daily_bonus" => perks.daily_bonus = perks.daily_bonus.saturating_add(value),
+                "work_bonus" => perks.work_bonus = perks.work_bonus.saturating_add(value),
+                _ => {}
+            }
+        }
+    }
+
+    perks.daily_multiplier = perks.daily_multiplier.min(config.max_multiplier);
+    perks.work_multiplier = perks.work_multiplier.min(config.max_multiplier);
+    perks.daily_bonus = perks.daily_bonus.min(config.max_bonus);
+    perks.work_bonus = perks.work_bonus.min(config.max_bonus);
+    perks
+}
+
+fn cached(principal: &Principal, now: u64) -> Option<Perks> {
+    let ttl = with(|config| config.cache_ttl);
+    CACHE.with(|c| {
+        c.borrow()
+            .get(principal)
+            .filter(|cached| now.saturating_sub(cached.fetched_at) < ttl)
+            .map(|cached| cached.perks.clone())
+    })
+}
+
+/// Get the perks of the tokens a principal owns, from the cache or the DIP721 canister.
+/// Failed lookups count as no perks, and are cached too so claims don't keep retrying.
+pub async fn get(principal: Principal) -> Perks {
+    let now = ic::time();
+    if let Some(perks) = cached(&principal, now) {
+        return perks;
+    }
+    let contract = match ledger::with(|ledger| ledger.nft_canister) {
+        Some(contract) => contract,
+        None => return Perks::default(),
+    };
+
+    let perks = match _DIP721v2Proxy::_owner_token_metadata(&contract, &principal).await {
+        Ok(tokens) => stack(&tokens),
+        // owners without tokens are reported as not found
+        Err(e) if e == "OwnerNotFound" => Perks::default(),
+        Err(e) => {
+            ic::print(format!("perk lookup failed for {}: {}", principal, e));
+            Perks::default()
+        }
+    };
+
+    let ttl = with(|config| config.cache_ttl);
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        if cache.len() > MAX_CACHED {
+            cache.retain(|_, cached| now.saturating_sub(cached.fetched_at) < ttl);
+        }
+        cache.insert(
+            principal,
+            Cached {
+                perks: perks.clone(),
+                fetched_at: now,
+            },
+        );
+    });
+    perks
+}
+
+/// Get a user's cached perks, empty if they haven't been looked up recently
+#[query]
+#[candid_method(query)]
+fn user_perks(discord_id: String) -> Option<Perks> {
+    let principal = ledger::with(|ledger| ledger.users.get(&discord_id).map(|u| u.principal))?;
+    cached(&principal, ic::time())
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_perk_config(config: PerkConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    // a cap under 1x would cut every reward
+    let res = if config.max_multiplier < 100 {
+        Err("Max multiplier must be at least 100 (1x)".to_string())
+    } else {
+        with_mut(|c| *c = config);
+        // cached perks were capped with the old config
+        CACHE.with(|c| c.borrow_mut().clear());
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_perk_config", args, &res);
+    res
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_perk_config() -> PerkConfig {
+    with(|config| config.clone())
+}
+
+// END CUSTODIAN METHODS //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::candid::Nat;
+
+    fn token(properties: Vec<(&str, u64)>, is_burned: bool) -> TokenMetadata {
+        TokenMetadata {
+            token_identifier: Nat::from(0),
+            owner: None,
+            operator: None,
+            is_burned,
+            properties: properties
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), GenericValue::Nat64Content(value)))
+                .collect(),
+            minted_at: 0,
+            minted_by: Principal::anonymous(),
+            transferred_at: None,
+            transferred_by: None,
+            approved_at: None,
+            approved_by: None,
+            burned_at: None,
+            burned_by: None,
+        }
+    }
+
+    #[test]
+    fn stack_adds_multipliers_and_bonuses() {
+        let perks = stack(&[
+            token(vec![("daily_multiplier", 150), ("work_bonus", 5)], false),
+            token(vec![("daily_multiplier", 150), ("work_bonus", 10)], false),
+            token(vec![("daily_multiplier", 200)], true),
+        ]);
+        assert_eq!(
+            perks,
+            Perks {
+                daily_multiplier: 200,
+                work_bonus: 15,
+                ..Perks::default()
+            }
+        );
+    }
+
+    #[test]
+    fn stack_caps_and_saturates() {
+        let perks = stack(&[
+            token(
+                vec![("work_multiplier", u64::MAX), ("daily_bonus", u64::MAX)],
+                false,
+            ),
+            token(
+                vec![("work_multiplier", u64::MAX), ("daily_bonus", u64::MAX)],
+                false,
+            ),
+        ]);
+        assert_eq!(perks.work_multiplier, 300);
+        assert_eq!(perks.daily_bonus, 1_000);
+    }
+
+    #[test]
+    fn rewards_apply_multiplier_then_bonus() {
+        let perks = Perks {
+            daily_multiplier: 150,
+            daily_bonus: 5,
+            ..Perks::default()
+        };
+        assert_eq!(perks.daily(100), 155);
+        assert_eq!(perks.work(100), 100);
+        assert_eq!(perks.daily(u64::MAX), u64::MAX / 100 + 5);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::audit;
+use crate::dip20;
+use crate::emission;
+use crate::ledger::{self, _is_auth};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+const ONE_DAY: u64 = 86_400_000_000_000;
+const ONE_WEEK: u64 = 7 * ONE_DAY;
+/// The unix epoch was a thursday, weeks roll over on monday
+const WEEK_OFFSET: u64 = 3 * ONE_DAY;
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    /// End of the period containing `now`, periods roll over at 00:00 UTC
+    fn ends_at(&self, now: u64) -> u64 {
+        match self {
+            Period::Daily => (now / ONE_DAY + 1) * ONE_DAY,
+            Period::Weekly => ((now + WEEK_OFFSET) / ONE_WEEK + 1) * ONE_WEEK - WEEK_OFFSET,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq)]
+pub enum Objective {
+    Daily,
+    Work,
+    Tip,
+    Purchase,
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct QuestTemplate {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub period: Period,
+    pub objective: Objective,
+    /// times the objective must be done
+    pub count: u64,
+    /// EMP paid on completion
+    pub reward: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct ActiveQuest {
+    pub id: u64,
+    pub template: QuestTemplate,
+    pub starts_at: u64,
+    pub ends_at: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Progress {
+    pub count: u64,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct QuestConfig {
+    /// quests active at once, per period
+    pub daily_quests: u64,
+    pub weekly_quests: u64,
+}
+
+impl Default for QuestConfig {
+    fn default() -> Self {
+        Self {
+            daily_quests: 3,
+            weekly_quests: 2,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Quests {
+    pub config: QuestConfig,
+    pub templates: BTreeMap<String, QuestTemplate>,
+    pub next_id: u64,
+    pub active: Vec<ActiveQuest>,
+    /// where the next rotation starts in the period's templates
+    pub rotation: HashMap<Period, usize>,
+    /// progress per user, per active quest id
+    pub progress: HashMap<String, HashMap<u64, Progress>>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct QuestStatus {
+    pub quest: ActiveQuest,
+    pub progress: u64,
+    pub completed_at: Option<u64>,
+}
+
+thread_local! {
+  static QUESTS: RefCell<Quests> = RefCell::new(Quests::default());
+}
+
+pub fn with<T, F: FnOnce(&Quests) -> T>(f: F) -> T {
+    QUESTS.with(|quests| f(&quests.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Quests) -> T>(f: F) -> T {
+    QUESTS.with(|quests| f(&mut quests.borrow_mut()))
+}
+
+/// Replace ended quests with the next templates in the rotation.
+/// Called from the canister heartbeat.
+pub fn rotate() {
+    let now = ic::time();
+    with_mut(|quests| {
+        let mut rotated = false;
+        for period in [Period::Daily, Period::Weekly] {
+            let current = quests
+                .active
+                .iter()
+                .any(|q| q.template.period == period && q.ends_at > now);
+            if current {
+                continue;
+            }
+
+            let templates: Vec<QuestTemplate> = quests
+                .templates
+                .values()
+                .filter(|t| t.period == period)
+                .cloned()
+                .collect();
+            let count = match period {
+                Period::Daily => quests.config.daily_quests,
+                Period::Weekly => quests.config.weekly_quests,
+            } as usize;
+            let offset = quests.rotation.entry(period).or_default();
+
+            rotated = true;
+
+            let mut picked = vec![];
+            for i in 0..count.min(templates.len()) {
+                picked.push(templates[(*offset + i) % templates.len()].clone());
+            }
+            *offset = (*offset + picked.len()) % templates.len().max(1);
+
+            quests
+                .active
+                .retain(|q| q.template.period != period || q.ends_at > now);
+            for template in picked {
+                let id = quests.next_id;
+                quests.next_id += 1;
+                quests.active.push(ActiveQuest {
+                    id,
+                    template,
+                    starts_at: now,
+                    ends_at: period.ends_at(now),
+                });
+            }
+        }
+
+        // progress only goes stale when quests were replaced
+        if !rotated {
+            return;
+        }
+        let active: Vec<u64> = quests.active.iter().map(|q| q.id).collect();
+        for progress in quests.progress.values_mut() {
+            progress.retain(|id, _| active.contains(id));
+        }
+        quests.progress.retain(|_, progress| !progress.is_empty());
+    })
+}
+
+/// Count an objective towards the user's active quests, paying out completed ones.
+/// Quests whose reward can't be paid stay open and complete again on the next
+/// objective. Returns the completed quests.
+pub fn record(discord_id: &str, objective: Objective) -> Vec<QuestTemplate> {
+    let now = ic::time();
+    let reached: Vec<(u64, QuestTemplate)> = with_mut(|quests| {
+        let progress = quests.progress.entry(discord_id.to_string()).or_default();
+        quests
+            .active
+            .iter()
+            .filter(|q| q.template.objective == objective && q.ends_at > now)
+            .filter_map(|q| {
+                let p = progress.entry(q.id).or_default();
+                if p.completed_at.is_some() {
+                    return None;
+                }
+                p.count += 1;
+                if p.count < q.template.count {
+                    return None;
+                }
+                Some((q.id, q.template.clone()))
+            })
+            .collect()
+    });
+
+    let reward: u64 = reached.iter().map(|(_, q)| q.reward).sum();
+    let principal = ledger::with(|ledger| ledger.users.get(discord_id).map(|u| u.principal));
+    if let Some(principal) = principal.filter(|_| reward > 0) {
+        let paid =
+            emission::spend(reward).and_then(|_| dip20::_mint_reward(principal, Nat::from(reward)));
+        if let Err(e) = paid {
+            ic::print(format!("quest reward not paid: {}", e));
+            return vec![];
+        }
+        ledger::with_mut(|ledger| {
+            if let Some(user) = ledger.users.get_mut(discord_id) {
+                user.total_rewards += reward;
+            }
+            ledger.log_reward(discord_id, reward, "quest", None);
+        });
+    }
+
+    with_mut(|quests| {
+        if let Some(progress) = quests.progress.get_mut(discord_id) {
+            for (id, _) in reached.iter() {
+                if let Some(p) = progress.get_mut(id) {
+                    p.completed_at = Some(now);
+                }
+            }
+        }
+    });
+    reached.into_iter().map(|(_, q)| q).collect()
+}
+
+/// Format completed quests for a bot response
+pub fn announce(completed: &[QuestTemplate]) -> String {
+    completed
+        .iter()
+        .map(|q| {
+            format!(
+                "\n:scroll: Quest complete: **{}** `+{} EMP`",
+                q.name, q.reward
+            )
+        })
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn active_quests() -> Vec<ActiveQuest> {
+    let now = ic::time();
+    with(|quests| {
+        quests
+            .active
+            .iter()
+            .filter(|q| q.ends_at > now)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Get a user's progress on the active quests
+#[query]
+#[candid_method(query)]
+fn quest_progress(discord_id: String) -> Vec<QuestStatus> {
+    let now = ic::time();
+    with(|quests| {
+        let progress = quests.progress.get(&discord_id);
+        quests
+            .active
+            .iter()
+            .filter(|q| q.ends_at > now)
+            .map(|q| {
+                let p = progress
+                    .and_then(|p| p.get(&q.id))
+                    .cloned()
+                    .unwrap_or_default();
+                QuestStatus {
+                    quest: q.clone(),
+                    progress: p.count,
+                    completed_at: p.completed_at,
+                }
+            })
+            .collect()
+    })
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Add or replace a quest template, it is picked up by the next rotation
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_quest_template(template: QuestTemplate) -> Result<(), String> {
+    let args = format!("{:?}", template);
+    let res = if template.count == 0 {
+        Err("Quest count must be at least 1".to_string())
+    } else {
+        with_mut(|quests| quests.templates.insert(template.id.clone(), template));
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_quest_template", args, &res);
+    res
+}
+
+/// Remove a quest template, active quests run until the end of their period
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn remove_quest_template(id: String) -> Result<(), String> {
+    let res = match with_mut(|quests| quests.templates.remove(&id)) {
+        Some(_) => Ok(()),
+        None => Err("Quest template not found".to_string()),
+    };
+    audit::record(
+        ic::caller(),
+        "remove_quest_template",
+        format!("id: {}", id),
+        &res,
+    );
+    res
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_quest_templates() -> Vec<QuestTemplate> {
+    with(|quests| quests.templates.values().cloned().collect())
+}
+
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_quest_config(config: QuestConfig) {
+    audit::record_ok("set_quest_config", format!("{:?}", config));
+    with_mut(|quests| quests.config = config);
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_quest_config() -> QuestConfig {
+    with(|quests| quests.config.clone())
+}
+
+// END CUSTODIAN METHODS //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daily_periods_end_at_midnight() {
+        assert_eq!(Period::Daily.ends_at(0), ONE_DAY);
+        assert_eq!(Period::Daily.ends_at(ONE_DAY + 1), 2 * ONE_DAY);
+        assert_eq!(Period::Daily.ends_at(2 * ONE_DAY - 1), 2 * ONE_DAY);
+    }
+
+    #[test]
+    fn weekly_periods_end_on_monday() {
+        // 1970-01-01 was a thursday, the first monday is 1970-01-05
+        let monday = 4 * ONE_DAY;
+        assert_eq!(Period::Weekly.ends_at(0), monday);
+        assert_eq!(Period::Weekly.ends_at(monday - 1), monday);
+        assert_eq!(Period::Weekly.ends_at(monday), monday + ONE_WEEK);
+    }
+}
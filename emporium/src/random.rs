@@ -0,0 +1,34 @@
+use ic_kit::{ic, Principal, RejectionCode};
+use sha2::{Digest, Sha256};
+
+/// Get 32 random bytes from the management canister. They come from the subnet's
+/// threshold randomness, so neither the caller nor a single node can predict them.
+pub async fn bytes() -> Result<Vec<u8>, String> {
+    let call_res: Result<(Vec<u8>,), (RejectionCode, String)> =
+        ic::call(Principal::management_canister(), "raw_rand", ()).await;
+
+    call_res
+        .map(|res| res.0)
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Read a number from the first 8 bytes of a `raw_rand` result
+pub fn to_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+/// Derive the `index`th number from a `raw_rand` result, as the first 8 bytes of
+/// sha256(bytes || index as big endian u64), for draws needing more than one number
+pub fn derive(bytes: &[u8], index: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher.update(index.to_be_bytes());
+    to_u64(&hasher.finalize())
+}
+
+/// Hex encode random bytes for cap records
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
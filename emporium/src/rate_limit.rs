@@ -0,0 +1,241 @@
+use crate::audit;
+use crate::ledger::_is_auth;
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const ONE_MINUTE: u64 = 60_000_000_000;
+
+/// Full buckets are pruned once this many are tracked
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token bucket limit: `capacity` calls in a burst, refilling one call every `refill_every` ns
+#[derive(Clone, Copy, Debug, Deserialize, CandidType)]
+pub struct Limit {
+    pub capacity: u64,
+    pub refill_every: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct RateLimits {
+    pub limits: HashMap<String, Limit>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let limit = |capacity, refill_every| Limit {
+            capacity,
+            refill_every,
+        };
+        Self {
+            limits: HashMap::from([
+                ("daily".to_string(), limit(3, 10 * ONE_MINUTE)),
+                ("work".to_string(), limit(3, 5 * ONE_MINUTE)),
+                ("register".to_string(), limit(3, 60 * ONE_MINUTE)),
+                ("set_principal".to_string(), limit(3, 60 * ONE_MINUTE)),
+                ("transfer".to_string(), limit(20, ONE_MINUTE / 2)),
+                ("transferFrom".to_string(), limit(20, ONE_MINUTE / 2)),
+                ("batch_transfer".to_string(), limit(3, 5 * ONE_MINUTE)),
+                ("approve".to_string(), limit(10, ONE_MINUTE)),
+                ("tip".to_string(), limit(10, ONE_MINUTE / 2)),
+                ("rain".to_string(), limit(3, 5 * ONE_MINUTE)),
+                ("coinflip".to_string(), limit(10, ONE_MINUTE / 2)),
+                ("dice".to_string(), limit(10, ONE_MINUTE / 2)),
+                ("buy_tickets".to_string(), limit(5, ONE_MINUTE)),
+                ("stake".to_string(), limit(5, ONE_MINUTE)),
+                ("unstake".to_string(), limit(5, ONE_MINUTE)),
+                ("governance_propose".to_string(), limit(3, 60 * ONE_MINUTE)),
+                ("governance_vote".to_string(), limit(10, ONE_MINUTE)),
+                ("list_item".to_string(), limit(10, ONE_MINUTE)),
+                ("buy_listing".to_string(), limit(10, ONE_MINUTE)),
+                ("bid".to_string(), limit(10, ONE_MINUTE)),
+            ]),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Principal(Principal),
+    Discord(String),
+}
+
+struct Bucket {
+    tokens: u64,
+    updated_at: u64,
+}
+
+thread_local! {
+  static RATE_LIMITS: RefCell<RateLimits> = RefCell::new(RateLimits::default());
+  // buckets are not persisted, upgrades refill everyone
+  static BUCKETS: RefCell<HashMap<(String, Key), Bucket>> = RefCell::new(HashMap::new());
+}
+
+pub fn with<T, F: FnOnce(&RateLimits) -> T>(f: F) -> T {
+    RATE_LIMITS.with(|limits| f(&limits.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut RateLimits) -> T>(f: F) -> T {
+    RATE_LIMITS.with(|limits| f(&mut limits.borrow_mut()))
+}
+
+fn refill(bucket: &mut Bucket, limit: &Limit, now: u64) {
+    let refilled = (now - bucket.updated_at) / limit.refill_every.max(1);
+    bucket.tokens = (bucket.tokens + refilled).min(limit.capacity);
+    if bucket.tokens == limit.capacity {
+        bucket.updated_at = now;
+    } else {
+        bucket.updated_at += refilled * limit.refill_every;
+    }
+}
+
+fn keys(caller: Principal, discord_id: Option<&str>) -> Vec<Key> {
+    let mut keys = vec![];
+    // custodians (the bot) call on behalf of every user, so only the discord id is limited
+    if _is_auth().is_err() {
+        keys.push(Key::Principal(caller));
+    }
+    if let Some(discord_id) = discord_id {
+        keys.push(Key::Discord(discord_id.to_string()));
+    }
+    keys
+}
+
+/// Take a token from the caller's and discord id's buckets for `method`,
+/// or err if either is empty
+pub fn check(method: &str, caller: Principal, discord_id: Option<&str>) -> Result<(), String> {
+    let limit = match with(|limits| limits.limits.get(method).copied()) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let now = ic::time();
+
+    BUCKETS.with(|b| {
+        let mut buckets = b.borrow_mut();
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|(method, _), bucket| {
+                let limit = with(|limits| limits.limits.get(method).copied());
+                match limit {
+                    Some(limit) => {
+                        refill(bucket, &limit, now);
+                        bucket.tokens < limit.capacity
+                    }
+                    None => false,
+                }
+            });
+        }
+
+        let keys: Vec<(String, Key)> = keys(caller, discord_id)
+            .into_iter()
+            .map(|key| (method.to_string(), key))
+            .collect();
+
+        for key in keys.iter() {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: limit.capacity,
+                updated_at: now,
+            });
+            refill(bucket, &limit, now);
+            if bucket.tokens == 0 {
+                let wait = limit.refill_every - (now - bucket.updated_at);
+                return Err(format!(
+                    "Slow down! Try again in {} seconds",
+                    wait / 1_000_000_000 + 1
+                ));
+            }
+        }
+
+        for key in keys.iter() {
+            buckets.get_mut(key).unwrap().tokens -= 1;
+        }
+        Ok(())
+    })
+}
+
+/// Check the caller's bucket without taking a token, for `inspect_message`
+pub fn peek(method: &str, caller: Principal) -> bool {
+    let limit = match with(|limits| limits.limits.get(method).copied()) {
+        Some(limit) => limit,
+        None => return true,
+    };
+    let now = ic::time();
+
+    BUCKETS.with(|b| {
+        let buckets = b.borrow();
+        match buckets.get(&(method.to_string(), Key::Principal(caller))) {
+            Some(bucket) => {
+                let refilled = (now - bucket.updated_at) / limit.refill_every.max(1);
+                bucket.tokens + refilled > 0
+            }
+            None => true,
+        }
+    })
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Set the rate limit for an update method, or remove it with `null`
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_rate_limit(method: String, limit: Option<Limit>) {
+    audit::record_ok("set_rate_limit", format!("{}: {:?}", method, limit));
+    with_mut(|limits| match limit {
+        Some(limit) => limits.limits.insert(method, limit),
+        None => limits.limits.remove(&method),
+    });
+}
+
+#[query(guard = "_is_auth")]
+#[candid_method(query)]
+fn get_rate_limits() -> Vec<(String, Limit)> {
+    with(|limits| {
+        limits
+            .limits
+            .iter()
+            .map(|(method, limit)| (method.clone(), *limit))
+            .collect()
+    })
+}
+
+// END CUSTODIAN METHODS //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        capacity: 3,
+        refill_every: 10,
+    };
+
+    #[test]
+    fn refill_adds_a_token_per_interval_keeping_the_remainder() {
+        let mut bucket = Bucket {
+            tokens: 0,
+            updated_at: 0,
+        };
+        refill(&mut bucket, &LIMIT, 25);
+        assert_eq!(bucket.tokens, 2);
+        assert_eq!(bucket.updated_at, 20);
+        refill(&mut bucket, &LIMIT, 29);
+        assert_eq!(bucket.tokens, 2);
+        refill(&mut bucket, &LIMIT, 30);
+        assert_eq!(bucket.tokens, 3);
+    }
+
+    #[test]
+    fn refill_stops_at_capacity() {
+        let mut bucket = Bucket {
+            tokens: 1,
+            updated_at: 0,
+        };
+        refill(&mut bucket, &LIMIT, 1_000);
+        assert_eq!(bucket.tokens, LIMIT.capacity);
+        assert_eq!(bucket.updated_at, 1_000);
+    }
+}
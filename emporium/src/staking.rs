@@ -0,0 +1,331 @@
+use crate::accounts;
+use crate::audit;
+use crate::dip20::{_history_inc, _transfer, balance_of, queue_cap_record, transfer_event};
+use crate::ledger::_is_auth;
+use crate::maintenance::{self, Subsystem};
+use crate::metrics;
+use crate::moderation;
+use crate::rate_limit;
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const ONE_DAY: u64 = 86_400_000_000_000;
+const MAX_BPS: u64 = 10_000;
+const MAX_POSITIONS: usize = 20;
+
+/// APR for locks of at least `min_days`
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AprTier {
+    pub min_days: u64,
+    pub apr_bps: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct StakingConfig {
+    /// the tier with the highest `min_days` a lock reaches applies
+    pub schedule: Vec<AprTier>,
+    pub max_lock_days: u64,
+    pub min_stake: Nat,
+    /// share of the principal kept on early exit, paid into the rewards budget
+    pub early_exit_penalty_bps: u64,
+}
+
+impl Default for StakingConfig {
+    fn default() -> Self {
+        let tier = |min_days, apr_bps| AprTier { min_days, apr_bps };
+        Self {
+            schedule: vec![tier(7, 200), tier(30, 500), tier(90, 800), tier(365, 1_200)],
+            max_lock_days: 365,
+            min_stake: Nat::from(100),
+            early_exit_penalty_bps: 1_000,
+        }
+    }
+}
+
+impl StakingConfig {
+    fn apr_bps(&self, lock_days: u64) -> Option<u64> {
+        self.schedule
+            .iter()
+            .filter(|tier| tier.min_days <= lock_days)
+            .max_by_key(|tier| tier.min_days)
+            .map(|tier| tier.apr_bps)
+    }
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Position {
+    pub id: u64,
+    pub owner: Principal,
+    pub amount: Nat,
+    pub apr_bps: u64,
+    /// yield paid at maturity, reserved from the budget when staking
+    pub reward: Nat,
+    pub staked_at: u64,
+    pub unlocks_at: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Staking {
+    pub config: StakingConfig,
+    pub next_id: u64,
+    pub positions: BTreeMap<u64, Position>,
+    pub total_staked: Nat,
+    /// yield owed to open positions, not available for new ones
+    pub reserved: Nat,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct StakingInfo {
+    pub total_staked: Nat,
+    pub positions: u64,
+    pub rewards_account: Principal,
+    /// budget left for the yield of new positions
+    pub rewards_available: Nat,
+    pub config: StakingConfig,
+}
+
+thread_local! {
+  static STAKING: RefCell<Staking> = RefCell::new(Staking::default());
+}
+
+pub fn with<T, F: FnOnce(&Staking) -> T>(f: F) -> T {
+    STAKING.with(|staking| f(&staking.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Staking) -> T>(f: F) -> T {
+    STAKING.with(|staking| f(&mut staking.borrow_mut()))
+}
+
+/// Rewards budget not reserved for open positions
+fn rewards_available(staking: &Staking) -> Nat {
+    let balance = balance_of(accounts::staking_rewards());
+    if balance > staking.reserved {
+        balance - staking.reserved.clone()
+    } else {
+        Nat::from(0)
+    }
+}
+
+/// Lock `amount` of the caller's balance for `lock_days`, earning the APR of the
+/// schedule tier it reaches. The yield is reserved from the rewards budget up front,
+/// so staking fails if the budget can't cover it.
+#[update]
+#[candid_method]
+fn stake(amount: Nat, lock_days: u64) -> Result<Position, String> {
+    let res = _stake(amount, lock_days);
+    metrics::observe("stake", &res);
+    res
+}
+
+fn _stake(amount: Nat, lock_days: u64) -> Result<Position, String> {
+    maintenance::ensure_active(Subsystem::Staking)?;
+    let caller = ic::caller();
+    rate_limit::check("stake", caller, None)?;
+    moderation::ensure_allowed_principal(caller)?;
+
+    let now = ic::time();
+    let position = with_mut(|staking| {
+        let config = &staking.config;
+        if amount < config.min_stake {
+            return Err(format!("Min stake is `{} EMP`", config.min_stake));
+        }
+        if lock_days > config.max_lock_days {
+            return Err(format!("Max lock is {} days", config.max_lock_days));
+        }
+        let apr_bps = config
+            .apr_bps(lock_days)
+            .ok_or("Lock is too short to earn yield")?;
+        if balance_of(caller) < amount {
+            return Err("Insufficient balance".to_string());
+        }
+        let open = staking
+            .positions
+            .values()
+            .filter(|p| p.owner == caller)
+            .count();
+        if open >= MAX_POSITIONS {
+            return Err(format!("Max {} open positions", MAX_POSITIONS));
+        }
+
+        let reward = amount.clone() * Nat::from(apr_bps * lock_days) / Nat::from(365 * MAX_BPS);
+        if reward > rewards_available(staking) {
+            return Err("The staking rewards budget can't cover this position".to_string());
+        }
+
+        let position = Position {
+            id: staking.next_id,
+            owner: caller,
+            amount: amount.clone(),
+            apr_bps,
+            reward: reward.clone(),
+            staked_at: now,
+            unlocks_at: now + lock_days * ONE_DAY,
+        };
+        staking.next_id += 1;
+        staking.total_staked += amount.clone();
+        staking.reserved += reward;
+        staking.positions.insert(position.id, position.clone());
+        Ok(position)
+    })?;
+
+    let pool = accounts::staking_pool();
+    _transfer(caller, pool, amount.clone());
+    _history_inc();
+    queue_cap_record(transfer_event(
+        caller,
+        "stake",
+        caller,
+        pool,
+        amount,
+        Nat::from(0),
+        now,
+    ));
+    Ok(position)
+}
+
+/// Close a position, returning what was paid out. At maturity the principal and
+/// yield are paid, before it the principal minus the early exit penalty.
+#[update]
+#[candid_method]
+fn unstake(position_id: u64) -> Result<Nat, String> {
+    let res = _unstake(position_id);
+    metrics::observe("unstake", &res);
+    res
+}
+
+fn _unstake(position_id: u64) -> Result<Nat, String> {
+    maintenance::ensure_active(Subsystem::Staking)?;
+    let caller = ic::caller();
+    rate_limit::check("unstake", caller, None)?;
+
+    let now = ic::time();
+    let (position, penalty_bps) = with_mut(|staking| {
+        match staking.positions.get(&position_id) {
+            Some(p) if p.owner == caller => {}
+            _ => return Err("Position not found".to_string()),
+        }
+        let position = staking.positions.remove(&position_id).unwrap();
+        staking.total_staked -= position.amount.clone();
+        staking.reserved -= position.reward.clone();
+        Ok((position, staking.config.early_exit_penalty_bps))
+    })?;
+
+    let pool = accounts::staking_pool();
+    let rewards = accounts::staking_rewards();
+    let zero = Nat::from(0);
+    let paid = if now >= position.unlocks_at {
+        _transfer(pool, caller, position.amount.clone());
+        queue_cap_record(transfer_event(
+            caller,
+            "unstake",
+            pool,
+            caller,
+            position.amount.clone(),
+            zero.clone(),
+            now,
+        ));
+        if position.reward > 0 {
+            _transfer(rewards, caller, position.reward.clone());
+            queue_cap_record(transfer_event(
+                caller,
+                "stake_yield",
+                rewards,
+                caller,
+                position.reward.clone(),
+                zero,
+                now,
+            ));
+        }
+        position.amount + position.reward
+    } else {
+        let penalty = position.amount.clone() * Nat::from(penalty_bps) / Nat::from(MAX_BPS);
+        let returned = position.amount - penalty.clone();
+        _transfer(pool, caller, returned.clone());
+        queue_cap_record(transfer_event(
+            caller,
+            "unstake",
+            pool,
+            caller,
+            returned.clone(),
+            zero.clone(),
+            now,
+        ));
+        if penalty > 0 {
+            _transfer(pool, rewards, penalty.clone());
+            queue_cap_record(transfer_event(
+                caller,
+                "stake_penalty",
+                pool,
+                rewards,
+                penalty,
+                zero,
+                now,
+            ));
+        }
+        returned
+    };
+    _history_inc();
+    Ok(paid)
+}
+
+/// Get the open positions of a principal
+#[query]
+#[candid_method(query)]
+fn staking_positions(owner: Principal) -> Vec<Position> {
+    with(|staking| {
+        staking
+            .positions
+            .values()
+            .filter(|p| p.owner == owner)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn total_staked() -> Nat {
+    with(|staking| staking.total_staked.clone())
+}
+
+/// Get the staking totals, rewards budget and APR schedule. Custodians fund the
+/// budget by transferring EMP to `rewards_account`.
+#[query]
+#[candid_method(query)]
+fn get_staking_info() -> StakingInfo {
+    with(|staking| StakingInfo {
+        total_staked: staking.total_staked.clone(),
+        positions: staking.positions.len() as u64,
+        rewards_account: accounts::staking_rewards(),
+        rewards_available: rewards_available(staking),
+        config: staking.config.clone(),
+    })
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Set the staking config, open positions keep the APR and yield they were opened with
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn set_staking_config(config: StakingConfig) -> Result<(), String> {
+    let args = format!("{:?}", config);
+    let res = if config.early_exit_penalty_bps > MAX_BPS {
+        Err(format!(
+            "Penalty can't be more than {} basis points",
+            MAX_BPS
+        ))
+    } else {
+        with_mut(|staking| staking.config = config);
+        Ok(())
+    };
+    audit::record(ic::caller(), "set_staking_config", args, &res);
+    res
+}
+
+// END CUSTODIAN METHODS //
@@ -0,0 +1,238 @@
+use crate::dip20::{
+    _announce_transfer, _batch_transfer, _charge_fee, _get_fee, _history_inc, _spend_allowance,
+    _transfer, allowance, balance_of, insert_into_cap, queue_cap_record,
+};
+use crate::ledger;
+use crate::maintenance::{self, Subsystem};
+use crate::metrics;
+use crate::moderation;
+use crate::quests::{self, Objective};
+use crate::rate_limit;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_kit::{
+    candid::{candid_method, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+
+const ONE_MINUTE: u64 = 60_000_000_000;
+const MAX_MEMO_SIZE: usize = 200;
+/// Max users a single rain is split across, the most recently active are picked
+const MAX_RAIN_RECIPIENTS: usize = 100;
+
+/// Look up the principals of a sender and recipient by discord id
+pub fn resolve(from: &str, to: &str) -> Result<(Principal, Principal), String> {
+    ledger::with(|ledger| {
+        let principal = |discord_id: &str| ledger.users.get(discord_id).map(|u| u.principal);
+        let from = principal(from).ok_or("Sender is not registered")?;
+        let to = principal(to).ok_or("Recipient is not registered")?;
+        Ok((from, to))
+    })
+}
+
+/// Check the sender can pay `total`, and that the caller may spend it: either the
+/// sender's principal, or a spender with enough allowance. Returns whether the
+/// caller spends through an allowance.
+pub fn ensure_spendable(
+    caller: Principal,
+    from_discord: &str,
+    from: Principal,
+    total: &Nat,
+) -> Result<bool, String> {
+    let delegated = caller != from;
+    if delegated && allowance(from, caller) < *total {
+        return Err(format!(
+            "<@{}>, approve the bot to spend at least `{} EMP` to send from discord",
+            from_discord, total
+        ));
+    }
+    if balance_of(from) < *total {
+        return Err(format!("<@{}>, insufficient balance", from_discord));
+    }
+    Ok(delegated)
+}
+
+/// Tip EMP from one discord user to another. The sender's principal can call
+/// this directly, anyone else (the bot) needs an allowance from the sender
+/// covering the amount and fee.
+#[update]
+#[candid_method]
+async fn tip(
+    from_discord: String,
+    to_discord: String,
+    amount: Nat,
+    memo: String,
+) -> Result<String, String> {
+    let res = _tip(from_discord, to_discord, amount, memo).await;
+    metrics::observe("tip", &res);
+    res
+}
+
+async fn _tip(
+    from_discord: String,
+    to_discord: String,
+    amount: Nat,
+    memo: String,
+) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Transfers)?;
+    let caller = ic::caller();
+    rate_limit::check("tip", caller, Some(&from_discord))?;
+    moderation::ensure_allowed(&from_discord)?;
+
+    if memo.len() > MAX_MEMO_SIZE {
+        return Err(format!("Memo is limited to {} bytes", MAX_MEMO_SIZE));
+    }
+    if amount == 0 {
+        return Err("Tip amount must be more than 0".to_string());
+    }
+    let (from, to) = resolve(&from_discord, &to_discord)?;
+    if from == to {
+        return Err(format!("<@{}>, you can't tip yourself", from_discord));
+    }
+
+    let fee = _get_fee();
+    let total = amount.clone() + fee.clone();
+    let delegated = ensure_spendable(caller, &from_discord, from, &total)?;
+
+    _charge_fee(from, fee.clone());
+    _transfer(from, to, amount.clone());
+    if delegated {
+        _spend_allowance(from, caller, total);
+    }
+    _history_inc();
+    _announce_transfer(from, to, &amount);
+
+    // the tip went through, a failed insert is queued in the tx log and retried
+    let _ = insert_into_cap(IndefiniteEvent {
+        caller,
+        operation: "tip".to_string(),
+        details: vec![
+            ("from".to_string(), DetailValue::from(from)),
+            ("to".to_string(), DetailValue::from(to)),
+            ("amount".to_string(), DetailValue::from(amount.clone())),
+            ("fee".to_string(), DetailValue::from(fee)),
+            (
+                "from_discord".to_string(),
+                DetailValue::Text(from_discord.clone()),
+            ),
+            (
+                "to_discord".to_string(),
+                DetailValue::Text(to_discord.clone()),
+            ),
+            ("memo".to_string(), DetailValue::Text(memo.clone())),
+            ("timestamp".to_string(), DetailValue::U64(ic::time())),
+        ],
+    })
+    .await;
+
+    let completed = quests::record(&from_discord, Objective::Tip);
+    Ok(format!(
+        "<@{}> tipped <@{}> `{} EMP`{}{}",
+        from_discord,
+        to_discord,
+        amount,
+        if memo.is_empty() {
+            String::new()
+        } else {
+            format!(": {}", memo)
+        },
+        quests::announce(&completed),
+    ))
+}
+
+/// Split `amount` evenly across members of a guild who claimed `daily` or `work`
+/// in the last `minutes`, like a tip. The remainder stays with the sender.
+/// Cap records are written from the heartbeat.
+#[update]
+#[candid_method]
+fn rain(
+    guild_id: String,
+    from_discord: String,
+    amount: Nat,
+    minutes: u64,
+) -> Result<String, String> {
+    let res = _rain(guild_id, from_discord, amount, minutes);
+    metrics::observe("rain", &res);
+    res
+}
+
+fn _rain(
+    guild_id: String,
+    from_discord: String,
+    amount: Nat,
+    minutes: u64,
+) -> Result<String, String> {
+    maintenance::ensure_active(Subsystem::Transfers)?;
+    let caller = ic::caller();
+    rate_limit::check("rain", caller, Some(&from_discord))?;
+    moderation::ensure_allowed(&from_discord)?;
+
+    let now = ic::time();
+    let window = minutes
+        .saturating_mul(ONE_MINUTE)
+        .min(ledger::ACTIVITY_WINDOW);
+    let (from, recipients) = ledger::with(|ledger| {
+        let from = ledger
+            .users
+            .get(&from_discord)
+            .map(|u| u.principal)
+            .ok_or("Sender is not registered")?;
+        let recipients: Vec<(String, Principal)> = ledger
+            .active_since(&guild_id, now - window)
+            .into_iter()
+            .filter(|discord_id| discord_id != &from_discord)
+            .filter(|discord_id| moderation::ensure_allowed(discord_id).is_ok())
+            .filter_map(|discord_id| {
+                let principal = ledger.users.get(&discord_id)?.principal;
+                Some((discord_id, principal))
+            })
+            .take(MAX_RAIN_RECIPIENTS)
+            .collect();
+        Ok::<_, String>((from, recipients))
+    })?;
+
+    if recipients.is_empty() {
+        return Err(format!(
+            "<@{}>, nobody has been active in the last {} minutes",
+            from_discord,
+            window / ONE_MINUTE
+        ));
+    }
+    let count = Nat::from(recipients.len());
+    let share = amount / count.clone();
+    if share == 0 {
+        return Err(format!(
+            "<@{}>, not enough to share with {} users",
+            from_discord,
+            recipients.len()
+        ));
+    }
+
+    let fee = _get_fee();
+    let total = (share.clone() + fee) * count.clone();
+    let delegated = ensure_spendable(caller, &from_discord, from, &total)?;
+
+    let transfers: Vec<(Principal, Nat)> = recipients
+        .iter()
+        .map(|(_, principal)| (*principal, share.clone()))
+        .collect();
+    for ie in _batch_transfer(caller, "rain", from, &transfers) {
+        queue_cap_record(ie);
+    }
+    if delegated {
+        _spend_allowance(from, caller, total);
+    }
+
+    Ok(format!(
+        "<@{}> made it rain `{} EMP` on {} users! {}",
+        from_discord,
+        share * count,
+        recipients.len(),
+        recipients
+            .iter()
+            .map(|(discord_id, _)| format!("<@{}>", discord_id))
+            .collect::<Vec<String>>()
+            .join(" ")
+    ))
+}
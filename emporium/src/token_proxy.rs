@@ -104,8 +104,32 @@ impl _DIP721v2Proxy {
         }
     }
 
+    pub async fn _mint(
+        contract: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+        properties: Vec<(String, GenericValue)>,
+    ) -> Result<Nat, String> {
+        let call_res: Result<(NftNatResult,), (RejectionCode, String)> =
+            ic::call(*contract, "mint", (*to, token_id.clone(), properties)).await;
+
+        call_res
+            .map_err(|err| format!("{:?}", err))?
+            .0
+            .map_err(|err| format!("{:?}", err))
+    }
+
     // Query Methods
 
+    pub async fn _total_supply(contract: &Principal) -> Result<Nat, String> {
+        let call_res: Result<(Nat,), (RejectionCode, String)> =
+            ic::call(*contract, "totalSupply", ()).await;
+
+        call_res
+            .map(|res| res.0)
+            .map_err(|err| format!("{:?}", err))
+    }
+
     pub async fn _token_metadata(
         token_id: &Nat,
         contract: &Principal,
@@ -119,6 +143,19 @@ impl _DIP721v2Proxy {
             .map_err(|err| format!("{:?}", err))
     }
 
+    pub async fn _owner_token_metadata(
+        contract: &Principal,
+        owner: &Principal,
+    ) -> Result<Vec<TokenMetadata>, String> {
+        let call_res: Result<(Result<Vec<TokenMetadata>, NftError>,), (RejectionCode, String)> =
+            ic::call(*contract, "ownerTokenMetadata", (*owner,)).await;
+
+        call_res
+            .map_err(|err| format!("{:?}", err))?
+            .0
+            .map_err(|err| format!("{:?}", err))
+    }
+
     pub async fn _owner_of(
         contract: &Principal,
         token_id: &Nat,
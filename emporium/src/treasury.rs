@@ -0,0 +1,178 @@
+use crate::accounts;
+use crate::audit;
+use crate::dip20::{_history_inc, _transfer, balance_of, queue_cap_record};
+use crate::ledger::_is_auth;
+use crate::metrics;
+use crate::multisig::{self, Operation};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_kit::{
+    candid::{candid_method, CandidType, Deserialize, Nat},
+    ic,
+    macros::*,
+    Principal,
+};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+const MAX_MEMO_SIZE: usize = 200;
+/// Spends kept for the report, older ones are only in cap
+const MAX_SPENDS: usize = 1_000;
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum Category {
+    Fees,
+    GameLosses,
+    Shop,
+    Marketplace,
+    Spending,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Spend {
+    pub id: u64,
+    pub to: Principal,
+    pub amount: Nat,
+    pub memo: String,
+    pub spent_by: Principal,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+pub struct Treasury {
+    pub inflows: HashMap<Category, Nat>,
+    pub outflows: HashMap<Category, Nat>,
+    pub next_id: u64,
+    pub spends: VecDeque<Spend>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct TreasuryReport {
+    pub account: Principal,
+    pub balance: Nat,
+    pub inflows: Vec<(Category, Nat)>,
+    pub outflows: Vec<(Category, Nat)>,
+    /// latest spends, newest first
+    pub spends: Vec<Spend>,
+}
+
+thread_local! {
+  static TREASURY: RefCell<Treasury> = RefCell::new(Treasury::default());
+}
+
+pub fn with<T, F: FnOnce(&Treasury) -> T>(f: F) -> T {
+    TREASURY.with(|treasury| f(&treasury.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut Treasury) -> T>(f: F) -> T {
+    TREASURY.with(|treasury| f(&mut treasury.borrow_mut()))
+}
+
+/// Count EMP already moved into the treasury towards a category
+pub fn record_inflow(category: Category, amount: &Nat) {
+    with_mut(|treasury| *treasury.inflows.entry(category).or_default() += amount.clone());
+}
+
+/// Move `amount` from `from` into the treasury, counted towards a category
+pub fn deposit(from: Principal, amount: Nat, category: Category) {
+    _transfer(from, accounts::treasury(), amount.clone());
+    record_inflow(category, &amount);
+}
+
+/// Pay `amount` out of the treasury, returning the spend id
+pub fn _spend(to: Principal, amount: Nat, memo: String) -> Result<Nat, String> {
+    if memo.is_empty() || memo.len() > MAX_MEMO_SIZE {
+        return Err(format!("Memo must be 1 to {} bytes", MAX_MEMO_SIZE));
+    }
+    if amount == 0 {
+        return Err("Amount must be more than 0".to_string());
+    }
+    let treasury = accounts::treasury();
+    if balance_of(treasury) < amount {
+        return Err("Insufficient treasury balance".to_string());
+    }
+
+    let caller = ic::caller();
+    let now = ic::time();
+    _transfer(treasury, to, amount.clone());
+    _history_inc();
+    let id = with_mut(|t| {
+        *t.outflows.entry(Category::Spending).or_default() += amount.clone();
+        let id = t.next_id;
+        t.next_id += 1;
+        t.spends.push_back(Spend {
+            id,
+            to,
+            amount: amount.clone(),
+            memo: memo.clone(),
+            spent_by: caller,
+            timestamp: now,
+        });
+        if t.spends.len() > MAX_SPENDS {
+            t.spends.pop_front();
+        }
+        id
+    });
+
+    queue_cap_record(IndefiniteEvent {
+        caller,
+        operation: "treasury_spend".to_string(),
+        details: vec![
+            ("from".to_string(), DetailValue::from(treasury)),
+            ("to".to_string(), DetailValue::from(to)),
+            ("amount".to_string(), DetailValue::from(amount)),
+            ("memo".to_string(), DetailValue::Text(memo)),
+            ("timestamp".to_string(), DetailValue::U64(now)),
+        ],
+    });
+    Ok(Nat::from(id))
+}
+
+/// Get the treasury balance, its inflows and outflows by category, and the latest spends
+#[query]
+#[candid_method(query)]
+fn treasury_report(limit: u64) -> TreasuryReport {
+    let account = accounts::treasury();
+    with(|treasury| TreasuryReport {
+        account,
+        balance: balance_of(account),
+        inflows: treasury
+            .inflows
+            .iter()
+            .map(|(category, amount)| (*category, amount.clone()))
+            .collect(),
+        outflows: treasury
+            .outflows
+            .iter()
+            .map(|(category, amount)| (*category, amount.clone()))
+            .collect(),
+        spends: treasury
+            .spends
+            .iter()
+            .rev()
+            .take(limit as usize)
+            .cloned()
+            .collect(),
+    })
+}
+
+// BEGIN CUSTODIAN METHODS //
+
+/// Spend from the treasury, with a memo saying what for.
+///
+/// With multisig enabled, spends above the mint threshold require a proposal.
+#[update(guard = "_is_auth")]
+#[candid_method]
+fn treasury_spend(to: Principal, amount: Nat, memo: String) -> Result<Nat, String> {
+    let args = format!("to: {}, amount: {}, memo: {}", to, amount, memo);
+    let res = multisig::ensure_direct(&Operation::TreasurySpend {
+        to,
+        amount: amount.clone(),
+        memo: memo.clone(),
+    })
+    .and_then(|_| _spend(to, amount, memo));
+    metrics::observe("treasury_spend", &res);
+    audit::record(ic::caller(), "treasury_spend", args, &res);
+    res
+}
+
+// END CUSTODIAN METHODS //